@@ -0,0 +1,161 @@
+use crate::id::Uid;
+use crate::ws::message::notification_msg::{BatchGift, OneGift};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// 合并连击后的一次送礼
+#[derive(Serialize, Debug, Clone)]
+pub struct GiftEvent {
+    pub uid: Uid,
+    pub uname: String,
+    pub gift_id: u32,
+    pub gift_name: String,
+    pub num: u32,
+    pub total_coin: u64,
+    pub coin_type: String,
+}
+
+struct Pending {
+    event: GiftEvent,
+    last_update: Instant,
+}
+
+/// 一次连击会收到多条 `SEND_GIFT` 和一条或多条 `COMBO_SEND` 汇总,
+/// 按 `batch_combo_id` 合并,连击超时后才输出最终的数量。
+pub struct ComboAggregator {
+    timeout: Duration,
+    pending: HashMap<String, Pending>,
+    seq: u64,
+}
+
+impl ComboAggregator {
+    pub fn new(timeout: Duration) -> Self {
+        ComboAggregator {
+            timeout,
+            pending: HashMap::new(),
+            seq: 0,
+        }
+    }
+
+    fn key(&mut self, batch_combo_id: &str, combo_id: &str, tid: &str) -> String {
+        [batch_combo_id, combo_id, tid]
+            .iter()
+            .find(|id| !id.is_empty())
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| {
+                self.seq += 1;
+                format!("seq:{}", self.seq)
+            })
+    }
+
+    pub fn add_gift(&mut self, gift: &OneGift, now: Instant) {
+        let key = self.key(
+            gift.batch_combo_id.as_str(),
+            gift.combo_id(),
+            gift.tid.as_str(),
+        );
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            event: GiftEvent {
+                uid: gift.uid,
+                uname: gift.uname.clone(),
+                gift_id: gift.gift_id,
+                gift_name: gift.gift_name.clone(),
+                num: 0,
+                total_coin: 0,
+                coin_type: gift.coin_type.clone(),
+            },
+            last_update: now,
+        });
+        pending.event.num += gift.num;
+        pending.event.total_coin += gift.total_coin as u64;
+        pending.last_update = now;
+    }
+
+    /// `COMBO_SEND` 带的是连击累计值,与已收到的 `SEND_GIFT` 取较大者
+    pub fn add_combo(&mut self, combo: &BatchGift, now: Instant) {
+        let key = self.key(combo.batch_combo_id.as_str(), combo.combo_id.as_str(), "");
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            event: GiftEvent {
+                uid: combo.uid,
+                uname: combo.uname.clone(),
+                gift_id: combo.gift_id,
+                gift_name: combo.gift_name.clone(),
+                num: 0,
+                total_coin: 0,
+                coin_type: String::new(),
+            },
+            last_update: now,
+        });
+        pending.event.num = pending.event.num.max(combo.total_num);
+        pending.event.total_coin = pending.event.total_coin.max(combo.combo_total_coin as u64);
+        pending.last_update = now;
+    }
+
+    /// 取出已经超时的连击
+    pub fn poll(&mut self, now: Instant) -> Vec<GiftEvent> {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.last_update) >= timeout)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut events: Vec<(Instant, GiftEvent)> = expired
+            .into_iter()
+            .filter_map(|k| self.pending.remove(&k))
+            .map(|p| (p.last_update, p.event))
+            .collect();
+        events.sort_by_key(|(t, _)| *t);
+        events.into_iter().map(|(_, e)| e).collect()
+    }
+
+    /// 不等超时,取出全部连击
+    pub fn flush(&mut self) -> Vec<GiftEvent> {
+        let mut events: Vec<(Instant, GiftEvent)> = self
+            .pending
+            .drain()
+            .map(|(_, p)| (p.last_update, p.event))
+            .collect();
+        events.sort_by_key(|(t, _)| *t);
+        events.into_iter().map(|(_, e)| e).collect()
+    }
+}
+
+#[test]
+fn combo_test() {
+    let gift = |num: u32| OneGift {
+        gift_id: 1,
+        gift_name: "小心心".to_string(),
+        total_coin: 100 * num,
+        coin_type: "gold".to_string(),
+        num,
+        uid: Uid(1),
+        uname: "A".to_string(),
+        tid: format!("tid{}", num),
+        batch_combo_id: "batch:1".to_string(),
+        combo_send: None,
+    };
+    let combo = BatchGift {
+        gift_id: 1,
+        gift_name: "小心心".to_string(),
+        total_num: 10,
+        combo_total_coin: 1000,
+        uid: Uid(1),
+        uname: "A".to_string(),
+        combo_id: "combo:1".to_string(),
+        batch_combo_id: "batch:1".to_string(),
+    };
+    let start = Instant::now();
+    let mut aggregator = ComboAggregator::new(Duration::from_secs(3));
+    aggregator.add_gift(&gift(1), start);
+    aggregator.add_gift(&gift(2), start + Duration::from_secs(1));
+    aggregator.add_combo(&combo, start + Duration::from_secs(2));
+    assert!(aggregator.poll(start + Duration::from_secs(4)).is_empty());
+
+    let events = aggregator.poll(start + Duration::from_secs(5));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].num, 10);
+    assert_eq!(events[0].total_coin, 1000);
+    assert_eq!(events[0].coin_type, "gold");
+}
@@ -0,0 +1,281 @@
+pub mod combo;
+
+use crate::archive::now_millis;
+use crate::id::{RoomId, Uid};
+use crate::ws::message::notification_msg::{GuardBuy, OneGift};
+use crate::ws::NotificationMsg;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// 1 元 = 1000 金瓜子
+pub const GOLD_PER_RMB: u64 = 1000;
+/// 1 电池 = 100 金瓜子
+pub const GOLD_PER_BATTERY: u64 = 100;
+
+const TOP_GIFTERS: usize = 10;
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct GiftTotal {
+    pub gift_name: String,
+    pub num: u64,
+    pub gold: u64,
+    pub silver: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct UserTotal {
+    pub uid: Uid,
+    pub uname: String,
+    pub gold: u64,
+    pub silver: u64,
+    /// 大航海花费 金瓜子
+    pub guard_gold: u64,
+}
+
+impl UserTotal {
+    pub fn total_gold(&self) -> u64 {
+        self.gold + self.guard_gold
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SessionReport {
+    pub room_id: RoomId,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub gold: u64,
+    pub silver: u64,
+    pub guard_gold: u64,
+    pub guard_count: u32,
+    pub battery: u64,
+    pub rmb: f64,
+    pub gifts: Vec<GiftTotal>,
+    pub top_gifters: Vec<UserTotal>,
+}
+
+/// 一场直播的礼物流水。
+/// 只统计 `SEND_GIFT`,`COMBO_SEND` 是连击的汇总,计入会重复。
+#[derive(Debug)]
+pub struct GiftLedger {
+    pub room_id: RoomId,
+    pub start_time: u64,
+    gold: u64,
+    silver: u64,
+    guard_gold: u64,
+    guard_count: u32,
+    gifts: HashMap<u32, GiftTotal>,
+    users: HashMap<Uid, UserTotal>,
+}
+
+impl GiftLedger {
+    pub fn new(room_id: RoomId, start_time: u64) -> Self {
+        GiftLedger {
+            room_id,
+            start_time,
+            gold: 0,
+            silver: 0,
+            guard_gold: 0,
+            guard_count: 0,
+            gifts: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    fn user(&mut self, uid: Uid, uname: &str) -> &mut UserTotal {
+        let user = self.users.entry(uid).or_insert_with(|| UserTotal {
+            uid,
+            ..Default::default()
+        });
+        if user.uname != uname {
+            user.uname = uname.to_string();
+        }
+        user
+    }
+
+    pub fn add_gift(&mut self, gift: &OneGift) {
+        let coin = gift.total_coin as u64;
+        let is_gold = gift.coin_type == "gold";
+        let total = self.gifts.entry(gift.gift_id).or_insert_with(|| GiftTotal {
+            gift_name: gift.gift_name.clone(),
+            ..Default::default()
+        });
+        total.num += gift.num as u64;
+        if is_gold {
+            total.gold += coin;
+            self.gold += coin;
+        } else {
+            total.silver += coin;
+            self.silver += coin;
+        }
+
+        let user = self.user(gift.uid, gift.uname.as_str());
+        if is_gold {
+            user.gold += coin;
+        } else {
+            user.silver += coin;
+        }
+    }
+
+    pub fn add_guard(&mut self, guard: &GuardBuy) {
+        let coin = guard.price as u64 * guard.num as u64;
+        self.guard_gold += coin;
+        self.guard_count += guard.num;
+        self.user(guard.uid, guard.username.as_str()).guard_gold += coin;
+    }
+
+    pub fn report(&self, end_time: u64) -> SessionReport {
+        let mut gifts: Vec<GiftTotal> = self.gifts.values().cloned().collect();
+        gifts.sort_by(|a, b| b.gold.cmp(&a.gold).then(b.silver.cmp(&a.silver)));
+
+        // 先比金瓜子,只送银瓜子礼物的用户排在后面
+        let mut top_gifters: Vec<UserTotal> = self
+            .users
+            .values()
+            .filter(|u| u.total_gold() > 0 || u.silver > 0)
+            .cloned()
+            .collect();
+        top_gifters.sort_by_key(|u| std::cmp::Reverse((u.total_gold(), u.silver)));
+        top_gifters.truncate(TOP_GIFTERS);
+
+        let gold = self.gold + self.guard_gold;
+        SessionReport {
+            room_id: self.room_id,
+            start_time: self.start_time,
+            end_time,
+            gold: self.gold,
+            silver: self.silver,
+            guard_gold: self.guard_gold,
+            guard_count: self.guard_count,
+            battery: gold / GOLD_PER_BATTERY,
+            rmb: gold as f64 / GOLD_PER_RMB as f64,
+            gifts,
+            top_gifters,
+        }
+    }
+}
+
+/// 按直播场次记账,下播时输出报告
+pub struct Accounting {
+    room_id: RoomId,
+    report_dir: Option<String>,
+    ledger: Option<GiftLedger>,
+}
+
+impl Accounting {
+    pub fn new(room_id: RoomId, report_dir: Option<String>) -> Self {
+        Accounting {
+            room_id,
+            report_dir,
+            ledger: None,
+        }
+    }
+
+    fn ledger(&mut self) -> &mut GiftLedger {
+        let room_id = self.room_id;
+        self.ledger
+            .get_or_insert_with(|| GiftLedger::new(room_id, now_millis()))
+    }
+
+    pub fn handle(&mut self, msg: &NotificationMsg) {
+        match msg {
+            // 没有收到 PREPARING 就再次开播时,上一场先结算
+            NotificationMsg::LIVE { .. } => {
+                self.finish();
+                self.ledger();
+            }
+            NotificationMsg::PREPARING {} => self.finish(),
+            NotificationMsg::SEND_GIFT { data } => self.ledger().add_gift(data),
+            NotificationMsg::GUARD_BUY { data } => self.ledger().add_guard(data),
+            _ => {}
+        }
+    }
+
+    /// 结束当前场次并输出报告
+    pub fn finish(&mut self) {
+        let ledger = match self.ledger.take() {
+            Some(ledger) => ledger,
+            None => return,
+        };
+        let report = ledger.report(now_millis());
+        info!(
+            "礼物统计 room={} 金瓜子={} 银瓜子={} 大航海={}个/{}金瓜子 电池={} 约{:.2}元",
+            report.room_id,
+            report.gold,
+            report.silver,
+            report.guard_count,
+            report.guard_gold,
+            report.battery,
+            report.rmb
+        );
+        for (i, user) in report.top_gifters.iter().enumerate() {
+            info!(
+                "  {}. {}({}) {}金瓜子 {}银瓜子",
+                i + 1,
+                user.uname,
+                user.uid,
+                user.total_gold(),
+                user.silver
+            );
+        }
+        if let Some(dir) = &self.report_dir {
+            if let Err(e) = write_report(dir, &report) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+pub fn write_report(dir: &str, report: &SessionReport) -> Result<(), Error> {
+    std::fs::create_dir_all(dir).map_err(|e| anyhow!("create {} {}", dir, e))?;
+    let path = format!("{}/gift_{}_{}.json", dir, report.room_id, report.start_time);
+    let json = serde_json::to_string_pretty(report).map_err(|e| anyhow!("{}", e))?;
+    std::fs::write(&path, json).map_err(|e| anyhow!("write {} {}", path, e))?;
+    info!("gift report saved to {}", path);
+    Ok(())
+}
+
+#[test]
+fn ledger_test() {
+    let gift = |uid: u64, coin: u32, coin_type: &str| OneGift {
+        gift_id: 1,
+        gift_name: "礼物".to_string(),
+        total_coin: coin,
+        coin_type: coin_type.to_string(),
+        num: 1,
+        uid: Uid(uid),
+        uname: format!("u{}", uid),
+        tid: String::new(),
+        batch_combo_id: String::new(),
+        combo_send: None,
+    };
+    let mut ledger = GiftLedger::new(RoomId(1), 0);
+    ledger.add_gift(&gift(1, 1000, "gold"));
+    ledger.add_gift(&gift(2, 500, "silver"));
+    ledger.add_gift(&gift(2, 100, "gold"));
+    ledger.add_gift(&gift(4, 900, "silver"));
+    ledger.add_guard(&GuardBuy {
+        gift_id: 10003,
+        gift_name: "舰长".to_string(),
+        guard_level: 3,
+        num: 1,
+        price: 198000,
+        uid: Uid(3),
+        username: "u3".to_string(),
+        start_time: 0,
+    });
+    let report = ledger.report(1);
+    assert_eq!(report.gold, 1100);
+    assert_eq!(report.silver, 1400);
+    assert_eq!(report.battery, 1991);
+    let top: Vec<u64> = report.top_gifters.iter().map(|u| u.uid.0).collect();
+    assert_eq!(top, vec![3, 1, 2, 4]);
+
+    // 重复开播时开始新的一场
+    let mut accounting = Accounting::new(RoomId(1), None);
+    accounting.handle(&NotificationMsg::LIVE { live_time: 0 });
+    let data = gift(1, 1000, "gold");
+    accounting.handle(&NotificationMsg::SEND_GIFT { data });
+    accounting.handle(&NotificationMsg::LIVE { live_time: 0 });
+    assert!(accounting.ledger.as_ref().unwrap().users.is_empty());
+}
@@ -0,0 +1,184 @@
+use crate::id::RoomId;
+use crate::ws::BackpressurePolicy;
+use crate::ws::NotificationMsg;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
+
+/// 存档中的一行,`time` 为收到消息时的毫秒时间戳
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Record {
+    pub time: u64,
+    #[serde(default)]
+    pub room_id: RoomId,
+    pub msg: NotificationMsg,
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+/// 以 json lines 格式追加写入直播消息
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("open archive {} {}", path, e))?;
+        Ok(Recorder {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, room_id: RoomId, msg: &NotificationMsg) -> Result<(), Error> {
+        self.write_line(&line(room_id, msg))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        self.writer
+            .write_all(line.as_bytes())
+            .and_then(|_| self.writer.write_all(b"\n"))
+            .map_err(|e| anyhow!("write archive {}", e))
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer
+            .flush()
+            .map_err(|e| anyhow!("flush archive {}", e))
+    }
+}
+
+fn line(room_id: RoomId, msg: &NotificationMsg) -> String {
+    serde_json::json!({ "time": now_millis(), "room_id": room_id, "msg": msg }).to_string()
+}
+
+enum Command {
+    Line(String),
+    Flush(oneshot::Sender<Result<(), Error>>),
+}
+
+/// 在单独的线程中写入存档,连接的消息循环只做序列化,不会被磁盘 io 阻塞
+#[derive(Clone)]
+pub struct ArchiveWriter {
+    tx: Sender<Command>,
+    policy: BackpressurePolicy,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ArchiveWriter {
+    /// 写入线程最多积压 `capacity` 条记录,之后按 `policy` 处理。
+    /// 所有 `ArchiveWriter` 都被丢弃后线程写完剩余的记录再退出
+    pub fn spawn(mut recorder: Recorder, capacity: usize, policy: BackpressurePolicy) -> Self {
+        let (tx, mut rx) = channel(capacity.max(1));
+        std::thread::Builder::new()
+            .name("archive".to_string())
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    match command {
+                        Command::Line(line) => {
+                            if let Err(e) = recorder.write_line(&line) {
+                                error!("{}", e);
+                            }
+                        }
+                        Command::Flush(done) => {
+                            let _ = done.send(recorder.flush());
+                        }
+                    }
+                }
+                if let Err(e) = recorder.flush() {
+                    error!("{}", e);
+                }
+            })
+            .expect("spawn archive thread");
+        ArchiveWriter {
+            tx,
+            policy,
+            dropped: Default::default(),
+        }
+    }
+
+    /// 积压已满时 `Block` 和 `Unbounded` 等待写入线程,其他策略丢弃这条记录
+    pub async fn record(&self, room_id: RoomId, msg: &NotificationMsg) -> Result<(), Error> {
+        let command = Command::Line(line(room_id, msg));
+        match self.policy {
+            BackpressurePolicy::Block | BackpressurePolicy::Unbounded => self
+                .tx
+                .send(command)
+                .await
+                .map_err(|_| anyhow!("archive writer stopped")),
+            _ => match self.tx.try_send(command) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if dropped % 100 == 1 {
+                        warn!("archive queue full, dropped {} records", dropped);
+                    }
+                    Ok(())
+                }
+                Err(TrySendError::Closed(_)) => Err(anyhow!("archive writer stopped")),
+            },
+        }
+    }
+
+    /// 等待之前的记录都写入文件
+    pub async fn flush(&self) -> Result<(), Error> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Command::Flush(done))
+            .await
+            .map_err(|_| anyhow!("archive writer stopped"))?;
+        wait.await.map_err(|_| anyhow!("archive writer stopped"))?
+    }
+}
+
+pub fn read_archive(path: &str) -> Result<Vec<Record>, Error> {
+    let file = File::open(path).map_err(|e| anyhow!("open archive {} {}", path, e))?;
+    let mut records = vec![];
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| anyhow!("read archive {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Record>(line.as_str()) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("archive line {} skip: {}", n + 1, e),
+        }
+    }
+    Ok(records)
+}
+
+#[tokio::test]
+async fn archive_writer_test() {
+    let path = std::env::temp_dir().join(format!("archive_test_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let recorder = Recorder::open(path).unwrap();
+    let writer = ArchiveWriter::spawn(recorder, 1, BackpressurePolicy::Block);
+    let live = NotificationMsg::LIVE { live_time: 1 };
+    writer.record(RoomId(1), &live).await.unwrap();
+    let preparing = NotificationMsg::PREPARING {};
+    writer.record(RoomId(2), &preparing).await.unwrap();
+    writer.flush().await.unwrap();
+    let records = read_archive(path).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].room_id, RoomId(2));
+    assert!(matches!(
+        records[0].msg,
+        NotificationMsg::LIVE { live_time: 1 }
+    ));
+    let _ = std::fs::remove_file(path);
+}
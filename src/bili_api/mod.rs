@@ -1,7 +1,11 @@
+use crate::id::{RoomId, Uid};
+use crate::metrics;
+use crate::proxy::Proxy;
 use anyhow::Error;
 use reqwest::cookie::{CookieStore, Jar};
 use reqwest::header::{ACCEPT, ORIGIN, REFERER, USER_AGENT};
-use reqwest::Client;
+use reqwest::{Client, ClientBuilder, RequestBuilder};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
@@ -16,6 +20,21 @@ const COOKIE_BILI_JCT: &'static str = "bili_jct=";
 const UA: &'static str =
     "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/81.0.4044.138 Safari/537.36";
 
+/// `proxy` 为空时直接连接
+fn client_builder(proxy: Option<&Proxy>) -> Result<ClientBuilder, Error> {
+    let builder = Client::builder()
+        .connect_timeout(Duration::from_secs(3))
+        .timeout(Duration::from_secs(5));
+    match proxy {
+        Some(proxy) => {
+            debug!("http proxy {}:{}", proxy.host, proxy.port);
+            let proxy = reqwest::Proxy::all(proxy.url().as_str()).map_err(|e| anyhow!("{}", e))?;
+            Ok(builder.proxy(proxy))
+        }
+        None => Ok(builder),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UserToken {
     pub uid: String,
@@ -29,6 +48,49 @@ pub struct APIClient {
     pub token: UserToken,
 }
 
+impl APIClient {
+    /// 不登录的游客客户端,uid 为 0。
+    /// 服务器给游客推送的弹幕用户名和 uid 会被打码,部分消息可能收不到
+    pub fn guest(proxy: Option<&Proxy>) -> Result<APIClient, Error> {
+        warn!("guest mode: uid=0, user names in danmu are masked");
+        let client = client_builder(proxy)?
+            .build()
+            .map_err(|e| anyhow!("{}", e))?;
+        let token = UserToken {
+            uid: "".to_string(),
+            token: "".to_string(),
+            csrf: "".to_string(),
+        };
+        Ok(APIClient { client, token })
+    }
+
+    pub fn is_guest(&self) -> bool {
+        self.token.uid.is_empty()
+    }
+
+    /// 游客为 0
+    pub fn uid(&self) -> Result<Uid, Error> {
+        if self.is_guest() {
+            return Ok(Uid(0));
+        }
+        self.token
+            .uid
+            .parse()
+            .map_err(|e| anyhow!("bad uid {} {}", self.token.uid, e))
+    }
+}
+
+#[test]
+fn test_guest_uid() {
+    let mut client = APIClient::guest(None).unwrap();
+    assert!(client.is_guest());
+    assert_eq!(client.uid().unwrap(), Uid(0));
+    client.token.uid = "4294967296".to_string();
+    assert_eq!(client.uid().unwrap(), Uid(4294967296));
+    client.token.uid = "abc".to_string();
+    assert!(client.uid().is_err());
+}
+
 fn check_cookie(jar: &Jar) -> Result<UserToken, Error> {
     let domain_url = BILI_URL.parse().unwrap();
     let cookies = jar
@@ -66,7 +128,7 @@ fn check_cookie(jar: &Jar) -> Result<UserToken, Error> {
     }
 }
 
-fn get_client_from_file() -> Result<APIClient, Error> {
+fn get_client_from_file(proxy: Option<&Proxy>) -> Result<APIClient, Error> {
     info!("get token from file `token`");
     let domain_url = BILI_URL.parse().unwrap();
     let jar = Jar::default();
@@ -76,10 +138,8 @@ fn get_client_from_file() -> Result<APIClient, Error> {
         jar.add_cookie_str(cookie, &domain_url);
     }
     let token = check_cookie(&jar)?;
-    let client = Client::builder()
+    let client = client_builder(proxy)?
         .cookie_provider(Arc::new(jar))
-        .connect_timeout(Duration::from_secs(3))
-        .timeout(Duration::from_secs(5))
         .build()
         .map_err(|e| anyhow!("{}", e))?;
     Ok(APIClient { client, token })
@@ -87,19 +147,19 @@ fn get_client_from_file() -> Result<APIClient, Error> {
 
 #[tokio::test]
 async fn test_get_client_from_bili() {
-    let r = get_client_from_bili().await.unwrap();
+    let r = get_client_from_bili(None).await.unwrap();
     println!("{}", r.token.uid)
 }
 
-async fn get_client_from_bili() -> Result<APIClient, Error> {
-    let login_url = get_login_url().await?;
+async fn get_client_from_bili(proxy: Option<&Proxy>) -> Result<APIClient, Error> {
+    let login_url = get_login_url(proxy).await?;
 
     if let Some(ref url) = login_url.data {
         print_login_qrcode(url.url.as_str());
 
         'check: loop {
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            let (client, login_result) = get_bili_client(url.qrcode_key.as_str()).await?;
+            let (client, login_result) = get_bili_client(url.qrcode_key.as_str(), proxy).await?;
             println!("{:?}", login_result);
             if login_result.code == 0 {
                 if let Some(r) = login_result.data {
@@ -118,15 +178,16 @@ async fn get_client_from_bili() -> Result<APIClient, Error> {
     Err(anyhow!("get login url error \n {:?}", login_url))
 }
 
-pub async fn get_client() -> Result<APIClient, Error> {
+/// 先读取保存的登录信息,失败时扫码登录。`proxy` 用于之后所有的接口请求
+pub async fn get_client(proxy: Option<&Proxy>) -> Result<APIClient, Error> {
     info!("get_client_from_file");
-    let maybe_client = get_client_from_file();
+    let maybe_client = get_client_from_file(proxy);
     match maybe_client {
         Ok(client) => Ok(client),
         Err(e) => {
             warn!("get_client_from_file {:?}", e);
             info!("get_client_from_bili");
-            get_client_from_bili().await
+            get_client_from_bili(proxy).await
         }
     }
 }
@@ -135,12 +196,37 @@ pub async fn get_client() -> Result<APIClient, Error> {
 async fn test_get_client() {
     println!("abc");
     env_logger::init();
-    let r = get_client().await;
+    let r = get_client(None).await;
     println!("{:?}", r);
 }
 
 // api
 
+/// 记录接口调用结果,`code` 为 None 表示请求或解析失败
+fn record_request(endpoint: &str, code: Option<i32>) {
+    let code = match code {
+        Some(code) => code.to_string(),
+        None => "error".to_string(),
+    };
+    let labels = [("endpoint", endpoint), ("code", code.as_str())];
+    metrics::inc(&metrics::API_REQUESTS, &labels);
+}
+
+async fn send_json<T: DeserializeOwned>(
+    endpoint: &str,
+    req: RequestBuilder,
+) -> Result<APIResult<T>, Error> {
+    let r = match req.send().await {
+        Ok(resp) => resp
+            .json::<APIResult<T>>()
+            .await
+            .map_err(|e| anyhow!("{} parse {}", endpoint, e)),
+        Err(e) => Err(anyhow!("{} request {}", endpoint, e)),
+    };
+    record_request(endpoint, r.as_ref().ok().map(|r| r.code));
+    r
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct APIResult<T> {
     #[serde(default)]
@@ -162,22 +248,19 @@ pub struct LoginUrl {
 
 #[tokio::test]
 async fn test_get_login_url() {
-    let login_url = get_login_url().await.unwrap();
+    let login_url = get_login_url(None).await.unwrap();
     println!("{:?}", login_url);
 }
 
-pub async fn get_login_url() -> Result<APIResult<LoginUrl>, Error> {
+pub async fn get_login_url(proxy: Option<&Proxy>) -> Result<APIResult<LoginUrl>, Error> {
     // https://passport.bilibili.com/x/passport-login/web/qrcode/generate?source=main-fe-header
-    let resp = reqwest::get(
+    let client = client_builder(proxy)?
+        .build()
+        .map_err(|e| anyhow!("{}", e))?;
+    let req = client.get(
         "https://passport.bilibili.com/x/passport-login/web/qrcode/generate?source=main-fe-header",
-    )
-    .await
-    .map_err(|e| anyhow!("request {:?}", e))?;
-    let r = resp
-        .json::<APIResult<LoginUrl>>()
-        .await
-        .map_err(|e| anyhow!("parse {:?}", e))?;
-    return Ok(r);
+    );
+    send_json("qrcode_generate", req).await
 }
 
 pub fn print_login_qrcode(login_url: &str) {
@@ -216,16 +299,17 @@ pub struct QrResult {
     message: String,
 }
 
-pub async fn get_bili_client(qrcode_key: &str) -> Result<(APIClient, APIResult<QrResult>), Error> {
+pub async fn get_bili_client(
+    qrcode_key: &str,
+    proxy: Option<&Proxy>,
+) -> Result<(APIClient, APIResult<QrResult>), Error> {
     info!("get_bili_client by {}", qrcode_key);
     // https://passport.biligame.com/x/passport-login/web/crossDomain?DedeUserID=16856350&DedeUserID__ckMd5=59f1d8365143ac66&Expires=1720765201&SESSDATA=56773412,1720765201,36615*12CjDbRK4JVBD6u2H_LA9a3C2px9CKaaCVwidTnrfjLYSIJc0PisIZZE2VRrNZMToXbIUSVngzNWo1b1loOFM1T25yVEMzZHM0bnRCdGdpWjVoeEhvVTRYME41MW5FMVFTRlpVbm9aWm1ZU2NTR2hkYndOeF9idTc3UlNVRFN6M2xDbml2ZV9ya1RBIIEC&bili_jct=6080ad32f93a316bbb1fc71cd30c6cd5&gourl=https%3A%2F%2Fwww.bilibili.com
 
     let jar = Arc::new(Jar::default());
 
-    let client = Client::builder()
+    let client = client_builder(proxy)?
         .cookie_provider(jar.clone())
-        .connect_timeout(Duration::from_secs(3))
-        .timeout(Duration::from_secs(5))
         .build()
         .map_err(|e| anyhow!("{}", e))?;
 
@@ -239,7 +323,10 @@ pub async fn get_bili_client(qrcode_key: &str) -> Result<(APIClient, APIResult<Q
         .form(&form_param)
         .send()
         .await
-        .map_err(|e| anyhow!("reqwest qrcode/poll error {}", e))?;
+        .map_err(|e| {
+            record_request("qrcode_poll", None);
+            anyhow!("reqwest qrcode/poll error {}", e)
+        })?;
 
     let header_cookies = resp.headers().get_all("set-cookie");
     let mut cookies = String::new();
@@ -259,10 +346,11 @@ pub async fn get_bili_client(qrcode_key: &str) -> Result<(APIClient, APIResult<Q
         cookies.pop();
     }
 
-    let r = resp
-        .json::<APIResult<QrResult>>()
-        .await
-        .map_err(|e| anyhow!("parse qrcode/poll respone error : {}", e))?;
+    let r = resp.json::<APIResult<QrResult>>().await.map_err(|e| {
+        record_request("qrcode_poll", None);
+        anyhow!("parse qrcode/poll respone error : {}", e)
+    })?;
+    record_request("qrcode_poll", Some(r.code));
 
     let token = if r.code == 0 && r.data.is_some() && r.data.as_ref().unwrap().code == 0 {
         let token = check_cookie(jar.as_ref())?;
@@ -286,9 +374,10 @@ pub async fn get_bili_client(qrcode_key: &str) -> Result<(APIClient, APIResult<Q
 
 pub async fn send_barrage(
     api_client: &APIClient,
-    room_id: &str,
+    room_id: RoomId,
     barrage: &str,
 ) -> Result<APIResult<serde_json::Value>, Error> {
+    let room_id = room_id.to_string();
     let now = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards");
@@ -299,32 +388,24 @@ pub async fn send_barrage(
         ("mode", "1"), // 1 是滚动弹幕 4 是底部弹幕
         ("msg", barrage),
         ("rnd", now.as_str()),
-        ("roomid", room_id),
+        ("roomid", room_id.as_str()),
         ("bubble", "0"),
         ("csrf_token", api_client.token.csrf.as_str()),
         ("csrf", api_client.token.csrf.as_str()),
     ];
-    let resp = api_client
+    let req = api_client
         .client
         .post("https://api.live.bilibili.com/msg/send")
         .header(USER_AGENT, UA)
         .header(reqwest::header::REFERER, "https://live.bilibili.com")
-        .form(&param)
-        .send()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-
-    let r = resp
-        .json::<APIResult<serde_json::Value>>()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-    Ok(r)
+        .form(&param);
+    send_json("send_barrage", req).await
 }
 
 #[tokio::test]
 async fn test_send_barrage() {
-    let client = get_client().await.unwrap();
-    let r = send_barrage(&client, "421296", "弹幕测试").await;
+    let client = get_client(None).await.unwrap();
+    let r = send_barrage(&client, RoomId(421296), "弹幕测试").await;
     println!("{:?}", r)
 }
 
@@ -337,49 +418,43 @@ pub enum BanUserResult {
 
 pub async fn ban_user(
     api_client: &APIClient,
-    room_id: &str,
-    block_uid: &str,
+    room_id: RoomId,
+    block_uid: Uid,
     hour: u32,
 ) -> Result<APIResult<BanUserResult>, Error> {
+    let room_id = room_id.to_string();
+    let block_uid = block_uid.to_string();
     let hour = format!("{}", hour);
     let param = [
-        ("roomid", room_id),
-        ("block_uid", block_uid),
+        ("roomid", room_id.as_str()),
+        ("block_uid", block_uid.as_str()),
         ("hour", hour.as_str()),
         ("csrf_token", api_client.token.csrf.as_str()),
         ("csrf", api_client.token.csrf.as_str()),
         ("visit_id", ""),
     ];
-    let resp = api_client
+    let req = api_client
         .client
         .post("https://api.live.bilibili.com/banned_service/v2/Silent/add_block_user")
         .header(USER_AGENT, UA)
         .header(reqwest::header::REFERER, "https://live.bilibili.com")
-        .form(&param)
-        .send()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-
-    let r = resp
-        .json::<APIResult<BanUserResult>>()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-    Ok(r)
+        .form(&param);
+    send_json("ban_user", req).await
 }
 
 #[tokio::test]
 async fn test_ban_user() {
-    let client = get_client().await.unwrap();
-    let r = ban_user(&client, "421296", "386121455", 1).await;
+    let client = get_client(None).await.unwrap();
+    let r = ban_user(&client, RoomId(421296), Uid(386121455), 1).await;
     println!("{:?}", r);
     tokio::time::sleep(Duration::from_millis(500)).await;
-    let r = ban_user(&client, "421295", "386121455", 1).await;
+    let r = ban_user(&client, RoomId(421295), Uid(386121455), 1).await;
     println!("{:?}", r)
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct FollowUser {
-    pub mid: u32,
+    pub mid: Uid,
     pub uname: String,
     pub mtime: u64,
 }
@@ -392,64 +467,48 @@ pub struct FollowResult {
 
 pub async fn get_some_followings(
     api_client: &APIClient,
-    uid: &str,
+    uid: Uid,
     page: u32,
     page_size: u32,
 ) -> Result<APIResult<FollowResult>, Error> {
-    let resp = api_client
+    let req = api_client
         .client
         .get(format!(
             "https://api.bilibili.com/x/relation/same/followings?vmid={}&ps={}&pn={}",
             uid, page_size, page
         ))
-        .header(USER_AGENT, UA)
-        .send()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-
-    let r = resp
-        .json::<APIResult<FollowResult>>()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-    Ok(r)
+        .header(USER_AGENT, UA);
+    send_json("get_some_followings", req).await
 }
 
 #[tokio::test]
 async fn test_get_some_followings() {
-    let client = get_client().await.unwrap();
-    let r = get_some_followings(&client, "2", 1, 50).await;
+    let client = get_client(None).await.unwrap();
+    let r = get_some_followings(&client, Uid(2), 1, 50).await;
     println!("{:?}", r);
 }
 
 pub async fn search_followings(
     api_client: &APIClient,
-    uid: u32,
+    uid: Uid,
     name: &str,
     page: u32,
     page_size: u32,
 ) -> Result<APIResult<FollowResult>, Error> {
-    let resp = api_client
+    let req = api_client
         .client
         .get(format!(
             "https://api.bilibili.com/x/relation/followings/search?vmid={}&name={}&ps={}&pn={}",
             uid, name, page_size, page
         ))
-        .header(USER_AGENT, UA)
-        .send()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-
-    let r = resp
-        .json::<APIResult<FollowResult>>()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-    Ok(r)
+        .header(USER_AGENT, UA);
+    send_json("search_followings", req).await
 }
 
 #[tokio::test]
 async fn test_search_followings() {
-    let client = get_client().await.unwrap();
-    let r = search_followings(&client, 2, "咬人猫", 1, 50).await;
+    let client = get_client(None).await.unwrap();
+    let r = search_followings(&client, Uid(2), "咬人猫", 1, 50).await;
     if let Ok(APIResult { data: Some(x), .. }) = &r {
         println!("{:?}", x);
     }
@@ -485,22 +544,14 @@ pub struct LiveHost {
 
 pub async fn get_danmu_info(
     api_client: &APIClient,
-    room_id: u32,
+    room_id: RoomId,
 ) -> Result<APIResult<DanmuInfoResult>, Error> {
-    let resp = api_client
+    let req = api_client
         .client
         .get(format!(
             "https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?id={}&type=0",
             room_id
         ))
-        .header(USER_AGENT, UA)
-        .send()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-
-    let r = resp
-        .json::<APIResult<DanmuInfoResult>>()
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
-    Ok(r)
+        .header(USER_AGENT, UA);
+    send_json("get_danmu_info", req).await
 }
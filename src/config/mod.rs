@@ -1,3 +1,8 @@
+use bilili_danmuji_rs::handler::reply::ReplyConfig;
+use bilili_danmuji_rs::handler::thanks::ThanksConfig;
+use bilili_danmuji_rs::proxy::Proxy;
+use bilili_danmuji_rs::ws::{BackpressurePolicy, ReconnectConfig, Transport};
+use bilili_danmuji_rs::RoomId;
 use serde::{Deserialize, Serialize};
 
 lazy_static! {
@@ -6,7 +11,105 @@ lazy_static! {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AppConfig {
-    pub room_id: u32,
+    /// 兼容旧配置中的单个 `room_id`
+    #[serde(alias = "room_id", deserialize_with = "one_or_many")]
+    pub room_ids: Vec<RoomId>,
+    /// 游客模式,不需要登录,uid 为 0,弹幕用户名会被打码
+    #[serde(default)]
+    pub guest: bool,
+    /// 消息存档路径,为空时不存档
+    #[serde(default)]
+    pub archive: Option<String>,
+    /// 礼物统计报告目录,为空时只打印日志
+    #[serde(default)]
+    pub gift_report_dir: Option<String>,
+    /// 舰队名单保存路径,为空时不记录。路径中的 `{room_id}` 会替换为直播间号
+    #[serde(default)]
+    pub guard_roster: Option<String>,
+    /// 连击超时秒数,超时后合并输出一次送礼
+    #[serde(default = "default_combo_timeout")]
+    pub combo_timeout: u64,
+    /// 断线重连策略,默认为不限次数的指数退避
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /// 超过这个秒数没有收到服务器消息就重连
+    #[serde(default = "default_liveness_timeout")]
+    pub liveness_timeout: u64,
+    /// 消息队列长度
+    #[serde(default = "default_channel_capacity")]
+    pub channel_capacity: usize,
+    /// 队列满时的处理方式: block drop_oldest drop_low_priority unbounded
+    #[serde(default)]
+    pub backpressure: BackpressurePolicy,
+    /// http 接口和 websocket 都通过这个代理连接,支持 `http://` 和 `socks5://`
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// 连接方式: websocket tcp
+    #[serde(default)]
+    pub transport: Transport,
+    /// 监控指标的监听地址,例如 `127.0.0.1:9100`,访问 `/metrics` 获取
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    /// 自动回复和答谢共用的发送间隔 毫秒,b站按账号限制发送频率
+    #[serde(default = "default_send_interval_ms")]
+    pub send_interval_ms: u64,
+    /// 未定义的 cmd 记录到这个文件,为空时只在内存中统计
+    #[serde(default)]
+    pub cmd_catalogue: Option<String>,
+    /// 弹幕关键词自动回复,游客模式下不生效
+    #[serde(default)]
+    pub auto_reply: ReplyConfig,
+    /// 自动答谢礼物,游客模式下不生效
+    #[serde(default)]
+    pub thanks: ThanksConfig,
+}
+
+fn default_send_interval_ms() -> u64 {
+    1500
+}
+
+fn default_channel_capacity() -> usize {
+    100
+}
+
+fn default_liveness_timeout() -> u64 {
+    90
+}
+
+fn default_combo_timeout() -> u64 {
+    5
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<RoomId>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(RoomId),
+        Many(Vec<RoomId>),
+    }
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(room_id) => Ok(vec![room_id]),
+        OneOrMany::Many(room_ids) => Ok(room_ids),
+    }
+}
+
+/// 按直播间区分的文件路径。没有 `{room_id}` 时只有配置中唯一的直播间使用原路径,
+/// 其他直播间(包括运行中新增的)在末尾追加直播间号
+pub fn room_path(path: &str, room_id: RoomId) -> String {
+    room_path_in(path, room_id, &APP_CONFIG.room_ids)
+}
+
+fn room_path_in(path: &str, room_id: RoomId, room_ids: &[RoomId]) -> String {
+    if path.contains("{room_id}") {
+        path.replace("{room_id}", room_id.to_string().as_str())
+    } else if room_ids == [room_id] {
+        path.to_string()
+    } else {
+        format!("{}.{}", path, room_id)
+    }
 }
 
 pub fn init_config() -> AppConfig {
@@ -14,38 +117,19 @@ pub fn init_config() -> AppConfig {
     serde_json::from_str(log_str.as_str()).unwrap()
 }
 
-pub fn logger_config() {
-    use log4rs::append::console::ConsoleAppender;
-    use log4rs::append::file::FileAppender;
-    use log4rs::config::{Appender, Config, Logger, Root};
-    use log4rs::encode::pattern::PatternEncoder;
-
-    let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "[{d(%Y-%m-%d %H:%M:%S)}] [{l}] {M} - {m} {n}",
-        )))
-        .build();
-    let file_log = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "[{d(%Y-%m-%d %H:%M:%S)}] [{l}] {M} - {m} {n}",
-        )))
-        .build("log")
-        .unwrap();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("file_log", Box::new(file_log)))
-        .logger(
-            Logger::builder()
-                .appender("file_log")
-                .build("bilili_danmuji_rs", log::LevelFilter::Info),
-        )
-        .build(
-            Root::builder()
-                .appender("stdout")
-                .build(log::LevelFilter::Debug),
-        )
-        .unwrap();
-
-    log4rs::init_config(config).unwrap();
+#[test]
+fn room_path_test() {
+    let one = [RoomId(1)];
+    assert_eq!(room_path_in("roster.json", RoomId(1), &one), "roster.json");
+    // 运行中新增的直播间不会覆盖配置中直播间的文件
+    assert_eq!(
+        room_path_in("roster.json", RoomId(2), &one),
+        "roster.json.2"
+    );
+    let two = [RoomId(1), RoomId(2)];
+    assert_eq!(
+        room_path_in("roster.json", RoomId(1), &two),
+        "roster.json.1"
+    );
+    assert_eq!(room_path_in("{room_id}.json", RoomId(2), &one), "2.json");
 }
@@ -0,0 +1,236 @@
+use crate::export::LiveSession;
+use crate::ws::message::notification_msg::DanmuMsg;
+use std::fmt::Write;
+
+#[derive(Debug, Clone)]
+pub struct AssOptions {
+    pub width: u32,
+    pub height: u32,
+    pub font_name: String,
+    pub font_size: u32,
+    /// 滚动弹幕从右到左经过屏幕的秒数
+    pub duration: f64,
+    /// 顶部/底部弹幕停留的秒数
+    pub fixed_duration: f64,
+    /// 滚动弹幕最多占用屏幕高度的比例 (0,1]
+    pub density: f64,
+    /// 不透明度 0-255
+    pub alpha: u8,
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        AssOptions {
+            width: 1920,
+            height: 1080,
+            font_name: "Microsoft YaHei".to_string(),
+            font_size: 48,
+            duration: 8.0,
+            fixed_duration: 4.0,
+            density: 0.8,
+            alpha: 0x30,
+        }
+    }
+}
+
+/// 每条轨道上最后一条弹幕,用于判断新弹幕会不会与它重叠
+#[derive(Clone, Copy)]
+struct LaneTail {
+    start: f64,
+    width: f64,
+}
+
+struct Layout<'a> {
+    opt: &'a AssOptions,
+    scroll: Vec<Option<LaneTail>>,
+    top: Vec<f64>,
+    bottom: Vec<f64>,
+}
+
+impl<'a> Layout<'a> {
+    fn new(opt: &'a AssOptions) -> Self {
+        let line_height = opt.font_size.max(1) as f64;
+        let scroll_lanes = (opt.height as f64 * opt.density.clamp(0.0, 1.0) / line_height) as usize;
+        let fixed_lanes = (opt.height as f64 / line_height) as usize;
+        Layout {
+            opt,
+            scroll: vec![None; scroll_lanes],
+            top: vec![f64::MIN; fixed_lanes],
+            bottom: vec![f64::MIN; fixed_lanes],
+        }
+    }
+
+    fn speed(&self, width: f64) -> f64 {
+        (self.opt.width as f64 + width) / self.opt.duration
+    }
+
+    /// 新弹幕完全进入屏幕前,前一条不能还在入口;
+    /// 新弹幕到达左边界前,前一条必须已经离开屏幕
+    fn scroll_lane(&mut self, start: f64, width: f64) -> Option<usize> {
+        let screen = self.opt.width as f64;
+        let duration = self.opt.duration;
+        let lane = self.scroll.iter().position(|tail| match tail {
+            None => true,
+            Some(tail) => {
+                let entered = tail.start + tail.width / self.speed(tail.width) <= start;
+                let caught = start + screen / self.speed(width) < tail.start + duration;
+                entered && !caught
+            }
+        })?;
+        self.scroll[lane] = Some(LaneTail { start, width });
+        Some(lane)
+    }
+
+    fn fixed_lane(lanes: &mut [f64], start: f64, duration: f64) -> Option<usize> {
+        let lane = lanes.iter().position(|end| *end <= start)?;
+        lanes[lane] = start + duration;
+        Some(lane)
+    }
+}
+
+/// 估算文字宽度,全角字符按一个字号,半角按半个字号
+fn text_width(text: &str, font_size: u32) -> f64 {
+    let units: u32 = text.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum();
+    units as f64 * font_size as f64 / 2.0
+}
+
+fn ass_time(secs: f64) -> String {
+    let cs = (secs.max(0.0) * 100.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+/// ass 颜色为 `&HBBGGRR`
+fn ass_color(rgb: u32) -> String {
+    let r = (rgb >> 16) & 0xff;
+    let g = (rgb >> 8) & 0xff;
+    let b = rgb & 0xff;
+    format!("&H{:02X}{:02X}{:02X}", b, g, r)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('{', "\\{")
+        .replace('}', "\\}")
+        .replace('\n', " ")
+}
+
+fn dialogue(ass: &mut String, start: f64, end: f64, effect: &str, msg: &DanmuMsg) {
+    let color = if msg.color == 0xffffff {
+        String::new()
+    } else {
+        format!("\\c{}", ass_color(msg.color))
+    };
+    let _ = writeln!(
+        ass,
+        "Dialogue: 0,{},{},Danmu,{},0,0,0,,{{{}{}}}{}",
+        ass_time(start),
+        ass_time(end),
+        msg.uname.replace(',', " "),
+        effect,
+        color,
+        escape(msg.text.as_str())
+    );
+}
+
+/// 把一场直播的弹幕渲染为 ass 字幕,放不下的弹幕会被丢弃
+pub fn to_ass(session: &LiveSession, opt: &AssOptions) -> String {
+    let mut ass = String::new();
+    let _ = write!(
+        ass,
+        "[Script Info]
+ScriptType: v4.00+
+Collisions: Normal
+PlayResX: {width}
+PlayResY: {height}
+WrapStyle: 2
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Danmu,{font},{size},&H{alpha:02X}FFFFFF,&H{alpha:02X}FFFFFF,&H{alpha:02X}000000,&H{alpha:02X}000000,0,0,0,0,100,100,0,0,1,1,0,7,0,0,0,0
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+",
+        width = opt.width,
+        height = opt.height,
+        font = opt.font_name,
+        size = opt.font_size,
+        alpha = opt.alpha,
+    );
+
+    let mut layout = Layout::new(opt);
+    let line_height = opt.font_size as f64;
+    let screen = opt.width as f64;
+    let mut dropped = 0;
+    for msg in &session.danmu {
+        let start = session.offset_of(msg) as f64 / 1000.0;
+        let width = text_width(msg.text.as_str(), opt.font_size);
+        match msg.mode {
+            4 | 5 => {
+                let lanes = if msg.mode == 4 {
+                    &mut layout.bottom
+                } else {
+                    &mut layout.top
+                };
+                let lane = match Layout::fixed_lane(lanes, start, opt.fixed_duration) {
+                    Some(lane) => lane,
+                    None => {
+                        dropped += 1;
+                        continue;
+                    }
+                };
+                let y = if msg.mode == 4 {
+                    opt.height as f64 - (lane + 1) as f64 * line_height
+                } else {
+                    lane as f64 * line_height
+                };
+                let effect = format!("\\an8\\pos({},{})", screen / 2.0, y);
+                dialogue(&mut ass, start, start + opt.fixed_duration, &effect, msg);
+            }
+            _ => {
+                let lane = match layout.scroll_lane(start, width) {
+                    Some(lane) => lane,
+                    None => {
+                        dropped += 1;
+                        continue;
+                    }
+                };
+                let y = lane as f64 * line_height;
+                let effect = format!("\\move({},{},{},{})", screen, y, -width, y);
+                dialogue(&mut ass, start, start + opt.duration, &effect, msg);
+            }
+        }
+    }
+    if dropped > 0 {
+        info!("ass export drop {} danmu", dropped);
+    }
+    ass
+}
+
+pub fn write_ass(session: &LiveSession, opt: &AssOptions, path: &str) -> Result<(), anyhow::Error> {
+    std::fs::write(path, to_ass(session, opt)).map_err(|e| anyhow!("write {} {}", path, e))
+}
+
+#[test]
+fn ass_lane_test() {
+    let opt = AssOptions {
+        height: 100,
+        font_size: 50,
+        density: 1.0,
+        ..Default::default()
+    };
+    let mut layout = Layout::new(&opt);
+    assert_eq!(layout.scroll_lane(0.0, 100.0), Some(0));
+    assert_eq!(layout.scroll_lane(0.0, 100.0), Some(1));
+    // 两条轨道都被占用
+    assert_eq!(layout.scroll_lane(0.1, 100.0), None);
+    assert_eq!(layout.scroll_lane(2.0, 100.0), Some(0));
+    assert_eq!(ass_time(3725.5), "1:02:05.50");
+    assert_eq!(ass_color(0x123456), "&H563412");
+}
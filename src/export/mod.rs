@@ -0,0 +1,69 @@
+pub mod ass;
+pub mod xml;
+
+use crate::archive::Record;
+use crate::ws::message::notification_msg::DanmuMsg;
+use crate::ws::NotificationMsg;
+
+/// 一场直播,从 `LIVE` 开始到 `PREPARING` 结束
+#[derive(Debug, Default)]
+pub struct LiveSession {
+    /// 开播时间 毫秒
+    pub start_time: u64,
+    pub end_time: Option<u64>,
+    pub danmu: Vec<DanmuMsg>,
+}
+
+impl LiveSession {
+    /// 弹幕相对开播的时间 毫秒
+    pub fn offset_of(&self, msg: &DanmuMsg) -> u64 {
+        msg.timestamp.saturating_sub(self.start_time)
+    }
+}
+
+/// 按 `LIVE`/`PREPARING` 把存档切分成多场直播,`records` 应来自同一个直播间。
+/// 存档开头没有 `LIVE` 时,以第一条记录的时间作为开播时间。
+pub fn split_sessions(records: Vec<Record>) -> Vec<LiveSession> {
+    let mut sessions = vec![];
+    let mut current: Option<LiveSession> = None;
+    for Record { time, msg, .. } in records {
+        match msg {
+            // 开播时会连续收到多条 LIVE
+            NotificationMsg::LIVE { live_time } if current.is_none() => {
+                let start_time = if live_time > 0 {
+                    live_time * 1000
+                } else {
+                    time
+                };
+                current = Some(LiveSession {
+                    start_time,
+                    ..Default::default()
+                });
+            }
+            NotificationMsg::PREPARING {} => {
+                if let Some(mut session) = current.take() {
+                    session.end_time = Some(time);
+                    sessions.push(session);
+                }
+            }
+            NotificationMsg::DANMU_MSG { info: mut msg }
+            | NotificationMsg::DANMU_MSG_N { info: mut msg } => {
+                if msg.timestamp == 0 {
+                    msg.timestamp = time;
+                }
+                current
+                    .get_or_insert_with(|| LiveSession {
+                        start_time: time,
+                        ..Default::default()
+                    })
+                    .danmu
+                    .push(msg);
+            }
+            _ => {}
+        }
+    }
+    if let Some(session) = current {
+        sessions.push(session);
+    }
+    sessions
+}
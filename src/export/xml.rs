@@ -0,0 +1,68 @@
+use crate::export::LiveSession;
+use std::fmt::Write;
+
+/// 导出为 B 站弹幕 xml 格式
+/// `<d p="时间,模式,字号,颜色,发送时间戳,弹幕池,用户hash,弹幕id">内容</d>`
+pub fn to_xml(session: &LiveSession) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<i>\n");
+    xml.push_str("  <chatserver>chat.bilibili.com</chatserver>\n");
+    xml.push_str("  <chatid>0</chatid>\n");
+    xml.push_str("  <mission>0</mission>\n");
+    let _ = writeln!(xml, "  <maxlimit>{}</maxlimit>", session.danmu.len());
+    xml.push_str("  <state>0</state>\n");
+    xml.push_str("  <real_name>0</real_name>\n");
+    xml.push_str("  <source>k-v</source>\n");
+    for (id, msg) in session.danmu.iter().enumerate() {
+        let _ = writeln!(
+            xml,
+            "  <d p=\"{:.5},{},{},{},{},0,{:x},{}\">{}</d>",
+            session.offset_of(msg) as f64 / 1000.0,
+            msg.mode,
+            msg.font_size,
+            msg.color,
+            msg.timestamp / 1000,
+            msg.uid.0,
+            id,
+            escape(msg.text.as_str())
+        );
+    }
+    xml.push_str("</i>\n");
+    xml
+}
+
+pub fn write_xml(session: &LiveSession, path: &str) -> Result<(), anyhow::Error> {
+    std::fs::write(path, to_xml(session)).map_err(|e| anyhow!("write {} {}", path, e))
+}
+
+fn escape(text: &str) -> String {
+    let mut s = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '"' => s.push_str("&quot;"),
+            '\'' => s.push_str("&apos;"),
+            // xml 1.0 不允许的控制字符
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+#[test]
+fn xml_test() {
+    let records = r#"{"time":1000,"msg":{"cmd":"LIVE"}}
+{"time":2000,"msg":{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,3500,0],"a<b",[1,"u"],[]]}}
+{"time":9000,"msg":{"cmd":"PREPARING"}}"#
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let sessions = crate::export::split_sessions(records);
+    assert_eq!(sessions.len(), 1);
+    let xml = to_xml(&sessions[0]);
+    assert!(xml.contains(r#"<d p="2.50000,1,25,16777215,3,0,1,0">a&lt;b</d>"#));
+}
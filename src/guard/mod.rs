@@ -0,0 +1,183 @@
+use crate::id::Uid;
+use crate::ws::message::notification_msg::GuardBuy;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// 大航海按 30 天一个月估算
+pub const MONTH_SECS: u64 = 30 * 24 * 60 * 60;
+pub const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuardPurchase {
+    pub uid: Uid,
+    pub username: String,
+    /// 1 总督 2 提督 3 舰长
+    pub guard_level: u32,
+    pub months: u32,
+    /// 购买时间 秒
+    pub time: u64,
+    /// 购买时仍在舰队中
+    pub renewal: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuardMember {
+    pub uid: Uid,
+    pub username: String,
+    pub guard_level: u32,
+    pub first_time: u64,
+    /// 预计到期时间 秒
+    pub expire_time: u64,
+    pub total_months: u32,
+}
+
+/// 舰队名单,每次上舰后保存到 json 文件
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct GuardRoster {
+    pub purchases: Vec<GuardPurchase>,
+    pub members: HashMap<Uid, GuardMember>,
+    #[serde(skip)]
+    path: String,
+}
+
+pub fn guard_name(guard_level: u32) -> &'static str {
+    match guard_level {
+        1 => "总督",
+        2 => "提督",
+        3 => "舰长",
+        _ => "未知",
+    }
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+impl GuardRoster {
+    /// 文件不存在时返回空名单
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut roster = match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str::<GuardRoster>(s.as_str())
+                .map_err(|e| anyhow!("parse {} {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => GuardRoster::default(),
+            Err(e) => return Err(anyhow!("read {} {}", path, e)),
+        };
+        roster.path = path.to_string();
+        Ok(roster)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| anyhow!("{}", e))?;
+        std::fs::write(&self.path, json).map_err(|e| anyhow!("write {} {}", self.path, e))
+    }
+
+    /// 记录一次上舰,到期时间在未过期时顺延
+    pub fn add(&mut self, buy: &GuardBuy, now: u64) -> &GuardPurchase {
+        let time = if buy.start_time > 0 {
+            buy.start_time
+        } else {
+            now
+        };
+        let duration = buy.num as u64 * MONTH_SECS;
+        let mut renewal = false;
+        let member = self.members.entry(buy.uid).or_insert_with(|| GuardMember {
+            uid: buy.uid,
+            username: buy.username.clone(),
+            guard_level: buy.guard_level,
+            first_time: time,
+            expire_time: time,
+            total_months: 0,
+        });
+        if member.expire_time > time {
+            renewal = true;
+            member.expire_time += duration;
+        } else {
+            member.expire_time = time + duration;
+        }
+        if buy.guard_level < member.guard_level || !renewal {
+            member.guard_level = buy.guard_level;
+        }
+        member.username = buy.username.clone();
+        member.total_months += buy.num;
+
+        self.purchases.push(GuardPurchase {
+            uid: buy.uid,
+            username: buy.username.clone(),
+            guard_level: buy.guard_level,
+            months: buy.num,
+            time,
+            renewal,
+        });
+        self.purchases.last().unwrap()
+    }
+
+    /// 当前仍在舰队中的成员,按等级排序
+    pub fn current(&self, now: u64) -> Vec<&GuardMember> {
+        let mut members: Vec<&GuardMember> = self
+            .members
+            .values()
+            .filter(|m| m.expire_time > now)
+            .collect();
+        members.sort_by_key(|m| (m.guard_level, m.first_time));
+        members
+    }
+
+    /// `days` 天内到期的成员,按到期时间排序
+    pub fn expiring_within(&self, now: u64, days: u64) -> Vec<&GuardMember> {
+        let deadline = now + days * DAY_SECS;
+        let mut members: Vec<&GuardMember> = self
+            .members
+            .values()
+            .filter(|m| m.expire_time > now && m.expire_time <= deadline)
+            .collect();
+        members.sort_by_key(|m| m.expire_time);
+        members
+    }
+
+    /// `since` 之后的续费记录
+    pub fn renewals(&self, since: u64) -> Vec<&GuardPurchase> {
+        self.purchases
+            .iter()
+            .filter(|p| p.renewal && p.time >= since)
+            .collect()
+    }
+}
+
+#[test]
+fn roster_test() {
+    let buy = |uid: u64, level: u32, num: u32, start_time: u64| GuardBuy {
+        gift_id: 10003,
+        gift_name: guard_name(level).to_string(),
+        guard_level: level,
+        num,
+        price: 198000,
+        uid: Uid(uid),
+        username: format!("u{}", uid),
+        start_time,
+    };
+    let mut roster = GuardRoster::default();
+    roster.add(&buy(1, 3, 1, 1000), 0);
+    roster.add(&buy(2, 3, 1, 1000), 0);
+    // 未过期续费,顺延一个月并升级
+    assert!(roster.add(&buy(1, 2, 1, 2000), 0).renewal);
+    assert_eq!(roster.members[&Uid(1)].expire_time, 1000 + 2 * MONTH_SECS);
+    assert_eq!(roster.members[&Uid(1)].guard_level, 2);
+
+    let now = 1000 + MONTH_SECS - DAY_SECS;
+    let current: Vec<u64> = roster.current(now).iter().map(|m| m.uid.0).collect();
+    assert_eq!(current, vec![1, 2]);
+    let expiring: Vec<u64> = roster
+        .expiring_within(now, 3)
+        .iter()
+        .map(|m| m.uid.0)
+        .collect();
+    assert_eq!(expiring, vec![2]);
+    assert_eq!(roster.renewals(0).len(), 1);
+
+    // 过期后再买不算续费
+    assert!(!roster.add(&buy(2, 3, 1, 1000 + 2 * MONTH_SECS), 0).renewal);
+}
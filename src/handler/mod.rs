@@ -0,0 +1,488 @@
+pub mod reply;
+pub mod thanks;
+
+use crate::bili_api::{send_barrage, APIClient};
+use crate::id::RoomId;
+use crate::ws::message::notification_msg::{
+    BatchGift, DanmuMsg, EntryEffect, GuardBuy, Interact, OneGift,
+};
+use crate::ws::{ConnectionState, MsgReceiver, NotificationMsg, RoomMessage, ServerLiveMessage};
+use anyhow::Error;
+use futures_util::future::{BoxFuture, FutureExt};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+pub type HandlerResult<'a> = BoxFuture<'a, Result<(), Error>>;
+
+/// 等待发送的弹幕超过这个数量时 `send_text` 返回错误
+const OUTBOX_CAPACITY: usize = 64;
+
+/// 所有处理器共享的上下文
+#[derive(Clone)]
+pub struct Context {
+    pub api_client: APIClient,
+    /// 弹幕由单独的任务按限速发送,处理器不用等待
+    outbox: Sender<(RoomId, String)>,
+}
+
+/// 两次发送之间至少间隔 `interval`,等待的调用按顺序依次通过
+pub struct SendLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<Option<tokio::time::Instant>>,
+}
+
+impl SendLimiter {
+    pub fn new(interval: Duration) -> Self {
+        SendLimiter {
+            interval,
+            next: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// 等到可以发送的时间
+    pub async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        let now = tokio::time::Instant::now();
+        let at = match *next {
+            Some(at) if at > now => {
+                tokio::time::sleep_until(at).await;
+                at
+            }
+            _ => now,
+        };
+        *next = Some(at + self.interval);
+    }
+}
+
+fn done<'a>() -> HandlerResult<'a> {
+    Box::pin(futures_util::future::ready(Ok(())))
+}
+
+/// 按字符截断到 `max_len`
+pub fn truncate(text: &str, max_len: usize) -> String {
+    text.chars().take(max_len).collect()
+}
+
+/// 把弹幕放入发送队列,发送失败只记录日志
+pub fn send_text(ctx: &Context, room_id: RoomId, text: &str) -> Result<(), Error> {
+    match ctx.outbox.try_send((room_id, text.to_string())) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(anyhow!("send queue full")),
+        Err(TrySendError::Closed(_)) => Err(anyhow!("send queue closed")),
+    }
+}
+
+/// 依次发送队列中的弹幕,b站按账号限制发送频率,所有处理器的弹幕共用一个限速
+async fn deliver(api_client: APIClient, limiter: SendLimiter, mut rx: Receiver<(RoomId, String)>) {
+    while let Some((room_id, text)) = rx.recv().await {
+        limiter.wait().await;
+        match send_barrage(&api_client, room_id, &text).await {
+            Ok(r) if r.code == 0 => {}
+            Ok(r) => error!(
+                "[{}] send barrage {} code={} {:?}",
+                room_id, text, r.code, r.message
+            ),
+            Err(e) => error!("[{}] send barrage {} {}", room_id, text, e),
+        }
+    }
+}
+
+/// 按消息类型处理直播间事件,只需要实现关心的方法。
+/// 每条通知消息先调用 `on_notification`,再调用对应类型的方法
+pub trait EventHandler: Send {
+    fn name(&self) -> &str;
+
+    fn on_connection<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _state: &'a ConnectionState,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_notification<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _msg: &'a NotificationMsg,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_live<'a>(&'a mut self, _ctx: &'a Context, _room_id: RoomId) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_preparing<'a>(&'a mut self, _ctx: &'a Context, _room_id: RoomId) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_danmu<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _msg: &'a DanmuMsg,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_interact<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _data: &'a Interact,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_entry_effect<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _data: &'a EntryEffect,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_gift<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _gift: &'a OneGift,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_combo<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _gift: &'a BatchGift,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    fn on_guard_buy<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        _room_id: RoomId,
+        _guard_buy: &'a GuardBuy,
+    ) -> HandlerResult<'a> {
+        done()
+    }
+
+    /// 每隔 `Pipeline::tick` 调用一次,用于超时合并之类的定时工作
+    fn on_tick<'a>(&'a mut self, _ctx: &'a Context, _now: Instant) -> HandlerResult<'a> {
+        done()
+    }
+
+    /// 消息流结束后调用一次
+    fn on_shutdown<'a>(&'a mut self, _ctx: &'a Context) -> HandlerResult<'a> {
+        done()
+    }
+}
+
+struct Registered {
+    order: i32,
+    handler: Box<dyn EventHandler>,
+}
+
+/// 按 `order` 从小到大依次调用处理器。
+/// 一个处理器返回错误或超时只记录日志,不影响其他处理器;panic 的处理器会被移除
+pub struct Pipeline {
+    ctx: Context,
+    /// `run` 时交给发送任务
+    outbox: Option<Receiver<(RoomId, String)>>,
+    send_interval: Duration,
+    handlers: Vec<Registered>,
+    tick: Duration,
+    timeout: Duration,
+}
+
+impl Pipeline {
+    /// `send_interval` 是同一账号两条弹幕的最小间隔
+    pub fn new(api_client: APIClient, send_interval: Duration) -> Self {
+        let (outbox, rx) = channel(OUTBOX_CAPACITY);
+        Pipeline {
+            ctx: Context { api_client, outbox },
+            outbox: Some(rx),
+            send_interval,
+            handlers: vec![],
+            tick: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// `order` 相同时按注册顺序
+    pub fn register<H: EventHandler + 'static>(mut self, order: i32, handler: H) -> Self {
+        let index = self.handlers.partition_point(|r| r.order <= order);
+        info!("register handler {} order={}", handler.name(), order);
+        self.handlers.insert(
+            index,
+            Registered {
+                order,
+                handler: Box::new(handler),
+            },
+        );
+        self
+    }
+
+    pub fn tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// 单个处理器一次调用的最长时间,超时后取消这次调用,默认 10 秒
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn handler_names(&self) -> Vec<&str> {
+        self.handlers.iter().map(|r| r.handler.name()).collect()
+    }
+
+    pub async fn dispatch(&mut self, message: &RoomMessage) {
+        let ctx = &self.ctx;
+        let mut crashed = vec![];
+        for (i, registered) in self.handlers.iter_mut().enumerate() {
+            let handler = registered.handler.as_mut();
+            let r = call(dispatch_one(handler, ctx, message), self.timeout).await;
+            if check(handler.name(), r) {
+                crashed.push(i);
+            }
+        }
+        self.remove(crashed);
+    }
+
+    async fn tick_all(&mut self, now: Instant) {
+        let ctx = &self.ctx;
+        let mut crashed = vec![];
+        for (i, registered) in self.handlers.iter_mut().enumerate() {
+            let handler = registered.handler.as_mut();
+            // 处理器可能在返回 future 之前就 panic,调用也要放在 catch_unwind 里
+            let r = call(async { handler.on_tick(ctx, now).await }, self.timeout).await;
+            if check(handler.name(), r) {
+                crashed.push(i);
+            }
+        }
+        self.remove(crashed);
+    }
+
+    fn remove(&mut self, crashed: Vec<usize>) {
+        for i in crashed.into_iter().rev() {
+            let registered = self.handlers.remove(i);
+            error!("handler {} panicked, removed", registered.handler.name());
+        }
+    }
+
+    /// 处理 `rx` 中的所有消息,结束后调用每个处理器的 `on_shutdown`,
+    /// 再等待队列中的弹幕发送完
+    pub async fn run(mut self, mut rx: MsgReceiver<Arc<RoomMessage>>) {
+        let sender = self.outbox.take().map(|outbox| {
+            let limiter = SendLimiter::new(self.send_interval);
+            tokio::spawn(deliver(self.ctx.api_client.clone(), limiter, outbox))
+        });
+        let mut tick = tokio::time::interval(self.tick);
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) => self.dispatch(&message).await,
+                    None => break,
+                },
+                _ = tick.tick() => self.tick_all(Instant::now()).await,
+            }
+        }
+        let ctx = &self.ctx;
+        for registered in self.handlers.iter_mut() {
+            let handler = registered.handler.as_mut();
+            let r = call(async { handler.on_shutdown(ctx).await }, self.timeout).await;
+            check(handler.name(), r);
+        }
+        if rx.dropped() > 0 {
+            warn!("{} messages dropped by backpressure", rx.dropped());
+        }
+        drop(self);
+        if let Some(sender) = sender {
+            let _ = sender.await;
+        }
+    }
+}
+
+/// 捕获 panic,超过 `timeout` 时取消并返回错误
+async fn call<F>(f: F, timeout: Duration) -> std::thread::Result<Result<(), Error>>
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    match tokio::time::timeout(timeout, AssertUnwindSafe(f).catch_unwind()).await {
+        Ok(r) => r,
+        Err(_) => Ok(Err(anyhow!("timed out after {:?}", timeout))),
+    }
+}
+
+/// 返回处理器是否 panic
+fn check(name: &str, r: std::thread::Result<Result<(), Error>>) -> bool {
+    match r {
+        Ok(Ok(())) => false,
+        Ok(Err(e)) => {
+            error!("handler {} {}", name, e);
+            false
+        }
+        Err(_) => true,
+    }
+}
+
+async fn dispatch_one(
+    handler: &mut dyn EventHandler,
+    ctx: &Context,
+    message: &RoomMessage,
+) -> Result<(), Error> {
+    let room_id = message.room_id;
+    let notification = match &message.msg {
+        ServerLiveMessage::Notification(notification) => notification,
+        ServerLiveMessage::Connection(state) => {
+            return handler.on_connection(ctx, room_id, state).await
+        }
+        ServerLiveMessage::LoginAck | ServerLiveMessage::ServerHeartBeat => return Ok(()),
+    };
+    handler.on_notification(ctx, room_id, notification).await?;
+    match notification {
+        NotificationMsg::LIVE { .. } => handler.on_live(ctx, room_id).await,
+        NotificationMsg::PREPARING {} => handler.on_preparing(ctx, room_id).await,
+        NotificationMsg::DANMU_MSG { info } | NotificationMsg::DANMU_MSG_N { info } => {
+            handler.on_danmu(ctx, room_id, info).await
+        }
+        NotificationMsg::INTERACT_WORD { data } => handler.on_interact(ctx, room_id, data).await,
+        NotificationMsg::ENTRY_EFFECT { data } => handler.on_entry_effect(ctx, room_id, data).await,
+        NotificationMsg::SEND_GIFT { data } => handler.on_gift(ctx, room_id, data).await,
+        NotificationMsg::COMBO_SEND { data } => handler.on_combo(ctx, room_id, data).await,
+        NotificationMsg::GUARD_BUY { data } => handler.on_guard_buy(ctx, room_id, data).await,
+        _ => Ok(()),
+    }
+}
+
+#[tokio::test]
+async fn send_limiter_test() {
+    let limiter = Arc::new(SendLimiter::new(Duration::from_millis(50)));
+    let start = Instant::now();
+    let waits: Vec<_> = (0..3)
+        .map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.wait().await })
+        })
+        .collect();
+    for wait in waits {
+        wait.await.unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    // 空闲之后不需要等待
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let start = Instant::now();
+    limiter.wait().await;
+    assert!(start.elapsed() < Duration::from_millis(40));
+}
+
+#[tokio::test]
+async fn pipeline_test() {
+    use std::sync::Mutex;
+
+    struct Probe {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl EventHandler for Probe {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn on_live<'a>(&'a mut self, _ctx: &'a Context, room_id: RoomId) -> HandlerResult<'a> {
+            Box::pin(async move {
+                self.seen
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", self.name, room_id));
+                match self.name {
+                    "fail" => Err(anyhow!("fail")),
+                    "slow" => {
+                        tokio::time::sleep(Duration::from_secs(3600)).await;
+                        Ok(())
+                    }
+                    "panic" => panic!("panic"),
+                    _ => Ok(()),
+                }
+            })
+        }
+
+        fn on_tick<'a>(&'a mut self, _ctx: &'a Context, _now: Instant) -> HandlerResult<'a> {
+            // 在返回 future 之前 panic
+            if self.name == "tick_panic" {
+                panic!("tick panic");
+            }
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let probe = |name| Probe {
+        name,
+        seen: seen.clone(),
+    };
+    let api_client = APIClient::guest(None).unwrap();
+    let mut pipeline = Pipeline::new(api_client, Duration::from_millis(1500))
+        .register(10, probe("last"))
+        .register(0, probe("fail"))
+        .register(5, probe("panic"))
+        .register(20, probe("tick_panic"))
+        .register(1, probe("slow"))
+        .handler_timeout(Duration::from_millis(50));
+    assert_eq!(
+        pipeline.handler_names(),
+        vec!["fail", "slow", "panic", "last", "tick_panic"]
+    );
+
+    let live = |room_id| RoomMessage {
+        room_id: RoomId(room_id),
+        msg: ServerLiveMessage::Notification(NotificationMsg::LIVE { live_time: 0 }),
+    };
+    pipeline.dispatch(&live(1)).await;
+    pipeline.dispatch(&live(2)).await;
+    // 超时的处理器不会被移除
+    assert_eq!(
+        pipeline.handler_names(),
+        vec!["fail", "slow", "last", "tick_panic"]
+    );
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            "fail 1",
+            "slow 1",
+            "panic 1",
+            "last 1",
+            "tick_panic 1",
+            "fail 2",
+            "slow 2",
+            "last 2",
+            "tick_panic 2"
+        ]
+    );
+
+    pipeline.tick_all(Instant::now()).await;
+    assert_eq!(pipeline.handler_names(), vec!["fail", "slow", "last"]);
+}
+
+#[tokio::test]
+async fn send_text_test() {
+    let api_client = APIClient::guest(None).unwrap();
+    let pipeline = Pipeline::new(api_client, Duration::from_secs(3600));
+    // 只放入队列,不等待限速
+    for i in 0..OUTBOX_CAPACITY {
+        send_text(&pipeline.ctx, RoomId(1), &i.to_string()).unwrap();
+    }
+    assert!(send_text(&pipeline.ctx, RoomId(1), "full").is_err());
+}
@@ -0,0 +1,320 @@
+use crate::handler::{send_text, truncate, Context, EventHandler, HandlerResult};
+use crate::id::{RoomId, Uid};
+use crate::ws::message::notification_msg::DanmuMsg;
+use anyhow::Error;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+fn default_max_len() -> usize {
+    20
+}
+
+/// 配置文件中的自动回复
+/// `{"max_len": 20, "rules": [{"trigger": {"contains": "晚上好"}, "reply": "{uname} 晚上好", "user_cooldown": 600}]}`
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ReplyConfig {
+    #[serde(default)]
+    pub rules: Vec<ReplyRule>,
+    /// 弹幕长度上限,超出部分截断
+    #[serde(default = "default_max_len")]
+    pub max_len: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// 弹幕内容完全相同
+    Exact(String),
+    Contains(String),
+    /// 回复中可以用 `{1}` `{name}` 引用捕获组
+    Regex(String),
+}
+
+/// 回复中的 `{uname}` `{uid}` `{text}` `{medal_name}` `{medal_lv}` 会替换为弹幕中的内容
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReplyRule {
+    pub trigger: Trigger,
+    pub reply: String,
+    /// 只在这些直播间生效,为空时所有直播间生效
+    #[serde(default)]
+    pub room_ids: Vec<RoomId>,
+    /// 要求佩戴的粉丝牌名称
+    #[serde(default)]
+    pub medal_name: Option<String>,
+    /// 要求粉丝牌等级不低于这个值
+    #[serde(default)]
+    pub medal_lv: Option<u32>,
+    /// 要求大航海等级,1 总督 2 提督 3 舰长,舰长及以上填 3
+    #[serde(default)]
+    pub guard_level: Option<u32>,
+    /// 同一直播间内这条规则两次回复的最小间隔 秒
+    #[serde(default)]
+    pub cooldown: u64,
+    /// 同一用户触发这条规则的最小间隔 秒
+    #[serde(default)]
+    pub user_cooldown: u64,
+}
+
+enum Matcher {
+    Exact(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+struct Rule {
+    matcher: Matcher,
+    config: ReplyRule,
+}
+
+impl Rule {
+    fn allowed(&self, room_id: RoomId, msg: &DanmuMsg) -> bool {
+        let config = &self.config;
+        if !config.room_ids.is_empty() && !config.room_ids.contains(&room_id) {
+            return false;
+        }
+        if let Some(medal_name) = &config.medal_name {
+            if &msg.medal_name != medal_name {
+                return false;
+            }
+        }
+        if let Some(medal_lv) = config.medal_lv {
+            if msg.medal_lv < medal_lv {
+                return false;
+            }
+        }
+        if let Some(guard_level) = config.guard_level {
+            if msg.guard_level == 0 || msg.guard_level > guard_level {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 匹配时返回替换好的回复
+    fn reply(&self, msg: &DanmuMsg) -> Option<String> {
+        let text = msg.text.trim();
+        let captures = match &self.matcher {
+            Matcher::Exact(s) if text == s => None,
+            Matcher::Contains(s) if text.contains(s.as_str()) => None,
+            Matcher::Regex(re) => Some(re.captures(text)?),
+            _ => return None,
+        };
+        Some(render(&self.config.reply, msg, captures.as_ref()))
+    }
+}
+
+/// 只替换模板中的占位符,替换进来的弹幕内容不会再被展开
+fn render(template: &str, msg: &DanmuMsg, captures: Option<&Captures>) -> String {
+    PLACEHOLDER
+        .replace_all(template, |c: &Captures| {
+            let key = &c[1];
+            let value = match key {
+                "uname" => Some(msg.uname.clone()),
+                "uid" => Some(msg.uid.to_string()),
+                "text" => Some(msg.text.trim().to_string()),
+                "medal_name" => Some(msg.medal_name.clone()),
+                "medal_lv" => Some(msg.medal_lv.to_string()),
+                _ => captures.and_then(|captures| {
+                    let group = match key.parse::<usize>() {
+                        Ok(i) if i < captures.len() => {
+                            Some(captures.get(i).map(|m| m.as_str()).unwrap_or_default())
+                        }
+                        Ok(_) => None,
+                        // 命名捕获组
+                        Err(_) => captures.name(key).map(|m| m.as_str()),
+                    };
+                    group.map(|s| s.to_string())
+                }),
+            };
+            value.unwrap_or_else(|| c[0].to_string())
+        })
+        .into_owned()
+}
+
+lazy_static! {
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{([A-Za-z0-9_]+)\}").unwrap();
+}
+
+/// 弹幕关键词自动回复,每条弹幕最多触发第一条匹配且不在冷却中的规则
+pub struct AutoReply {
+    rules: Vec<Rule>,
+    max_len: usize,
+    /// (规则, 直播间) -> 上次回复时间
+    room_last: HashMap<(usize, RoomId), Instant>,
+    /// (规则, 直播间, 用户) -> 上次回复时间
+    user_last: HashMap<(usize, RoomId, Uid), Instant>,
+}
+
+impl AutoReply {
+    pub fn new(config: &ReplyConfig) -> Result<Self, Error> {
+        let mut rules = vec![];
+        for rule in config.rules.iter() {
+            let matcher = match &rule.trigger {
+                Trigger::Exact(s) => Matcher::Exact(s.clone()),
+                Trigger::Contains(s) => Matcher::Contains(s.clone()),
+                Trigger::Regex(s) => {
+                    Matcher::Regex(Regex::new(s).map_err(|e| anyhow!("bad regex {} {}", s, e))?)
+                }
+            };
+            rules.push(Rule {
+                matcher,
+                config: rule.clone(),
+            });
+        }
+        Ok(AutoReply {
+            rules,
+            max_len: config.max_len,
+            room_last: HashMap::new(),
+            user_last: HashMap::new(),
+        })
+    }
+
+    /// 找到匹配且不在冷却中的规则,记录冷却并返回回复内容
+    pub fn reply_for(&mut self, room_id: RoomId, msg: &DanmuMsg, now: Instant) -> Option<String> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.allowed(room_id, msg) {
+                continue;
+            }
+            let reply = match rule.reply(msg) {
+                Some(reply) => reply,
+                None => continue,
+            };
+            let cooling = |last: Option<&Instant>, secs: u64| match last {
+                Some(last) => now.duration_since(*last) < Duration::from_secs(secs),
+                None => false,
+            };
+            let room_key = (i, room_id);
+            let user_key = (i, room_id, msg.uid);
+            if cooling(self.room_last.get(&room_key), rule.config.cooldown)
+                || cooling(self.user_last.get(&user_key), rule.config.user_cooldown)
+            {
+                debug!("[{}] reply rule {} cooling down", room_id, i);
+                continue;
+            }
+            self.room_last.insert(room_key, now);
+            if rule.config.user_cooldown > 0 {
+                self.user_last.insert(user_key, now);
+            }
+            return Some(truncate(&reply, self.max_len));
+        }
+        None
+    }
+
+    /// 弹幕用户很多,冷却结束的记录要定时清掉
+    fn prune(&mut self, now: Instant) {
+        let rules = &self.rules;
+        self.user_last.retain(|(i, _, _), last| {
+            let cooldown = Duration::from_secs(rules[*i].config.user_cooldown);
+            now.duration_since(*last) < cooldown
+        });
+    }
+}
+
+impl EventHandler for AutoReply {
+    fn name(&self) -> &str {
+        "auto_reply"
+    }
+
+    fn on_danmu<'a>(
+        &'a mut self,
+        ctx: &'a Context,
+        room_id: RoomId,
+        msg: &'a DanmuMsg,
+    ) -> HandlerResult<'a> {
+        Box::pin(async move {
+            // 不回复自己发的弹幕
+            if ctx.api_client.uid()? == msg.uid {
+                return Ok(());
+            }
+            let reply = match self.reply_for(room_id, msg, Instant::now()) {
+                Some(reply) => reply,
+                None => return Ok(()),
+            };
+            info!("[{}] 自动回复 {}: {}", room_id, msg.uname, reply);
+            send_text(ctx, room_id, &reply)
+        })
+    }
+
+    fn on_tick<'a>(&'a mut self, _ctx: &'a Context, now: Instant) -> HandlerResult<'a> {
+        self.prune(now);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[test]
+fn auto_reply_test() {
+    let config: ReplyConfig = serde_json::from_str(
+        r#"{"max_len": 10, "rules": [
+            {"trigger": {"exact": "签到"}, "reply": "{uname} 签到成功", "user_cooldown": 60},
+            {"trigger": {"regex": "^点歌 (?P<song>.+)$"}, "reply": "已点 {song}", "guard_level": 3},
+            {"trigger": {"contains": "晚上好"}, "reply": "晚上好呀 {uname}", "cooldown": 60, "room_ids": [1]},
+            {"trigger": {"regex": "^复读 (.+)$"}, "reply": "{1}{medal_name}{x}"},
+            {"trigger": {"contains": "签到"}, "reply": "又来签到"}
+        ]}"#,
+    )
+    .unwrap();
+    let mut bot = AutoReply::new(&config).unwrap();
+    let danmu = |uid: u64, text: &str, guard_level: u32| DanmuMsg {
+        uid: Uid(uid),
+        uname: format!("u{}", uid),
+        medal_lv: 0,
+        medal_name: String::new(),
+        medal_owner_uid: Uid(0),
+        medal_owner_name: String::new(),
+        guard_level,
+        text: text.to_string(),
+        mode: 1,
+        font_size: 25,
+        color: 0xffffff,
+        timestamp: 0,
+    };
+    let now = Instant::now();
+    let later = now + Duration::from_secs(61);
+    let room = RoomId(1);
+
+    assert_eq!(
+        bot.reply_for(room, &danmu(1, "签到", 0), now),
+        Some("u1 签到成功".to_string())
+    );
+    // 用户冷却中时继续匹配后面的规则,其他用户不受影响
+    assert_eq!(
+        bot.reply_for(room, &danmu(1, "签到", 0), now),
+        Some("又来签到".to_string())
+    );
+    assert_eq!(
+        bot.reply_for(room, &danmu(2, " 签到 ", 0), now),
+        Some("u2 签到成功".to_string())
+    );
+    assert!(bot.reply_for(room, &danmu(1, "签到", 0), later).is_some());
+    // 冷却结束的记录被清掉
+    bot.prune(later);
+    assert_eq!(bot.user_last.len(), 1);
+
+    assert_eq!(bot.reply_for(room, &danmu(1, "点歌 晴天", 0), now), None);
+    assert_eq!(
+        bot.reply_for(room, &danmu(1, "点歌 一首很长很长的歌名", 3), now),
+        Some("已点 一首很长很长的".to_string())
+    );
+
+    // 直播间冷却
+    assert!(bot
+        .reply_for(room, &danmu(1, "大家晚上好", 0), now)
+        .is_some());
+    assert_eq!(bot.reply_for(room, &danmu(2, "晚上好", 0), now), None);
+    assert_eq!(
+        bot.reply_for(RoomId(2), &danmu(2, "晚上好", 0), later),
+        None
+    );
+
+    // 弹幕内容中的占位符不会再被替换
+    assert_eq!(
+        bot.reply_for(room, &danmu(1, "复读 {uid}", 0), now),
+        Some("{uid}{x}".to_string())
+    );
+
+    let bad: ReplyConfig =
+        serde_json::from_str(r#"{"rules": [{"trigger": {"regex": "("}, "reply": ""}]}"#).unwrap();
+    assert!(AutoReply::new(&bad).is_err());
+}
@@ -0,0 +1,394 @@
+use crate::accounting::combo::{ComboAggregator, GiftEvent};
+use crate::guard::guard_name;
+use crate::handler::{send_text, truncate, Context, EventHandler, HandlerResult};
+use crate::id::{RoomId, Uid};
+use crate::ws::message::notification_msg::{BatchGift, GuardBuy, OneGift};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+fn default_prefix() -> String {
+    "谢谢".to_string()
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+fn default_interval_secs() -> u64 {
+    5
+}
+
+fn default_max_messages() -> usize {
+    3
+}
+
+fn default_max_len() -> usize {
+    20
+}
+
+/// 配置文件中的礼物答谢
+/// `{"enabled": true, "window_secs": 10, "interval_secs": 5, "max_messages": 3, "max_len": 20}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ThanksConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// 收到第一份礼物后等待这么多秒,把期间的礼物合并成一批答谢
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// 同一直播间两条答谢弹幕的最小间隔 秒
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// 一批礼物最多发送的弹幕数,超出的用户合并为一句
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+    /// 弹幕长度上限
+    #[serde(default = "default_max_len")]
+    pub max_len: usize,
+    /// 只答谢金瓜子礼物
+    #[serde(default)]
+    pub gold_only: bool,
+}
+
+impl Default for ThanksConfig {
+    fn default() -> Self {
+        ThanksConfig {
+            enabled: false,
+            prefix: default_prefix(),
+            window_secs: default_window_secs(),
+            interval_secs: default_interval_secs(),
+            max_messages: default_max_messages(),
+            max_len: default_max_len(),
+            gold_only: false,
+        }
+    }
+}
+
+/// 一批中同一用户的同一种礼物
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    uid: Uid,
+    uname: String,
+    gift_name: String,
+    num: u32,
+    guard: bool,
+}
+
+impl Entry {
+    fn text(&self) -> String {
+        self.text_with(&self.uname)
+    }
+
+    fn text_with(&self, uname: &str) -> String {
+        if self.guard {
+            format!("{} 的 {}", uname, self.gift_name)
+        } else {
+            format!("{} 的 {} 个{}", uname, self.num, self.gift_name)
+        }
+    }
+
+    /// 超过 `max_len` 时缩短用户名,保留礼物部分
+    fn text_within(&self, max_len: usize) -> String {
+        let text = self.text();
+        let len = text.chars().count();
+        if len <= max_len {
+            return text;
+        }
+        let uname_len = self.uname.chars().count();
+        let keep = (uname_len + max_len).checked_sub(len + 1);
+        match keep {
+            Some(keep) if keep > 0 => {
+                let uname: String = self.uname.chars().take(keep).collect();
+                self.text_with(&format!("{}…", uname))
+            }
+            // 礼物部分本身就放不下
+            _ => truncate(&text, max_len),
+        }
+    }
+}
+
+struct RoomThanks {
+    combo: ComboAggregator,
+    entries: Vec<Entry>,
+    window_start: Option<Instant>,
+    outbox: VecDeque<String>,
+    last_sent: Option<Instant>,
+}
+
+/// 合并一段时间内的礼物,按长度限制拼成答谢弹幕并限速发送
+pub struct Thanks {
+    config: ThanksConfig,
+    combo_timeout: Duration,
+    rooms: HashMap<RoomId, RoomThanks>,
+}
+
+impl Thanks {
+    /// `combo_timeout` 与统计使用的连击超时相同
+    pub fn new(config: ThanksConfig, combo_timeout: Duration) -> Self {
+        Thanks {
+            config,
+            combo_timeout,
+            rooms: HashMap::new(),
+        }
+    }
+
+    fn room(&mut self, room_id: RoomId) -> &mut RoomThanks {
+        let combo_timeout = self.combo_timeout;
+        self.rooms.entry(room_id).or_insert_with(|| RoomThanks {
+            combo: ComboAggregator::new(combo_timeout),
+            entries: vec![],
+            window_start: None,
+            outbox: VecDeque::new(),
+            last_sent: None,
+        })
+    }
+
+    fn add_entry(&mut self, room_id: RoomId, entry: Entry, now: Instant) {
+        let room = self.room(room_id);
+        room.window_start.get_or_insert(now);
+        let same = room.entries.iter_mut().find(|e| {
+            e.uid == entry.uid && e.gift_name == entry.gift_name && e.guard == entry.guard
+        });
+        match same {
+            Some(same) => same.num += entry.num,
+            None => room.entries.push(entry),
+        }
+    }
+
+    fn add_event(&mut self, room_id: RoomId, event: GiftEvent, now: Instant) {
+        if self.config.gold_only && event.coin_type != "gold" {
+            return;
+        }
+        let entry = Entry {
+            uid: event.uid,
+            uname: event.uname,
+            gift_name: event.gift_name,
+            num: event.num,
+            guard: false,
+        };
+        self.add_entry(room_id, entry, now);
+    }
+
+    pub fn add_guard(&mut self, room_id: RoomId, guard_buy: &GuardBuy, now: Instant) {
+        let entry = Entry {
+            uid: guard_buy.uid,
+            uname: guard_buy.username.clone(),
+            gift_name: guard_name(guard_buy.guard_level).to_string(),
+            num: guard_buy.num,
+            guard: true,
+        };
+        self.add_entry(room_id, entry, now);
+    }
+
+    /// 取出连击已结束的礼物,窗口结束且上一批已发完时生成新一批答谢,
+    /// 返回现在可以发送的弹幕
+    pub fn poll(&mut self, now: Instant) -> Vec<(RoomId, String)> {
+        let mut events = vec![];
+        for (room_id, room) in self.rooms.iter_mut() {
+            for event in room.combo.poll(now) {
+                events.push((*room_id, event));
+            }
+        }
+        for (room_id, event) in events {
+            self.add_event(room_id, event, now);
+        }
+
+        let window = Duration::from_secs(self.config.window_secs);
+        let interval = Duration::from_secs(self.config.interval_secs);
+        let mut ready = vec![];
+        for (room_id, room) in self.rooms.iter_mut() {
+            let window_end = room
+                .window_start
+                .map(|start| now.duration_since(start) >= window);
+            // 上一批没发完时继续累积,礼物刷屏时自然合并成更大的一批
+            if window_end == Some(true) && room.outbox.is_empty() {
+                let entries = std::mem::take(&mut room.entries);
+                room.outbox = compose(&entries, &self.config).into();
+                room.window_start = None;
+            }
+            let can_send = match room.last_sent {
+                Some(last_sent) => now.duration_since(last_sent) >= interval,
+                None => true,
+            };
+            if can_send {
+                if let Some(text) = room.outbox.pop_front() {
+                    room.last_sent = Some(now);
+                    ready.push((*room_id, text));
+                }
+            }
+        }
+        ready
+    }
+}
+
+/// 把一批礼物拼成不超过 `max_len` 的弹幕,最多 `max_messages` 条
+fn compose(entries: &[Entry], config: &ThanksConfig) -> Vec<String> {
+    let prefix = config.prefix.as_str();
+    let len = |s: &str| s.chars().count();
+    // (弹幕, 包含的条目数)
+    let mut messages: Vec<(String, usize)> = vec![];
+    for entry in entries {
+        let text = entry.text();
+        if let Some((message, count)) = messages.last_mut() {
+            if len(message) + 2 + len(&text) <= config.max_len {
+                message.push_str(", ");
+                message.push_str(&text);
+                *count += 1;
+                continue;
+            }
+        }
+        let max_len = config.max_len.saturating_sub(len(prefix) + 1);
+        let message = format!("{} {}", prefix, entry.text_within(max_len));
+        messages.push((truncate(&message, config.max_len), 1));
+    }
+    let max_messages = config.max_messages.max(1);
+    if messages.len() > max_messages {
+        let sent: usize = messages[..max_messages - 1].iter().map(|(_, c)| c).sum();
+        let mut uids = HashSet::new();
+        for entry in entries[sent..].iter() {
+            uids.insert(entry.uid);
+        }
+        messages.truncate(max_messages - 1);
+        let rest = if messages.is_empty() {
+            format!("{} {} 位的礼物", prefix, uids.len())
+        } else {
+            format!("{} 其他 {} 位的礼物", prefix, uids.len())
+        };
+        messages.push((truncate(&rest, config.max_len), 0));
+    }
+    messages.into_iter().map(|(m, _)| m).collect()
+}
+
+impl EventHandler for Thanks {
+    fn name(&self) -> &str {
+        "thanks"
+    }
+
+    fn on_gift<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        gift: &'a OneGift,
+    ) -> HandlerResult<'a> {
+        self.room(room_id).combo.add_gift(gift, Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_combo<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        gift: &'a BatchGift,
+    ) -> HandlerResult<'a> {
+        self.room(room_id).combo.add_combo(gift, Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_guard_buy<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        guard_buy: &'a GuardBuy,
+    ) -> HandlerResult<'a> {
+        self.add_guard(room_id, guard_buy, Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_tick<'a>(&'a mut self, ctx: &'a Context, now: Instant) -> HandlerResult<'a> {
+        let ready = self.poll(now);
+        Box::pin(async move {
+            // 每个直播间的弹幕互不影响,失败的只记录日志
+            for (room_id, text) in ready {
+                info!("[{}] 答谢: {}", room_id, text);
+                if let Err(e) = send_text(ctx, room_id, &text) {
+                    error!("[{}] 答谢发送失败 {}: {}", room_id, text, e);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn thanks_test() {
+    let gift = |uid: u64, uname: &str, gift_name: &str, num: u32| OneGift {
+        gift_id: 1,
+        gift_name: gift_name.to_string(),
+        total_coin: 100 * num,
+        coin_type: "gold".to_string(),
+        num,
+        uid: Uid(uid),
+        uname: uname.to_string(),
+        tid: format!("tid{}{}", uid, num),
+        batch_combo_id: String::new(),
+        combo_send: None,
+    };
+    let config = ThanksConfig {
+        enabled: true,
+        max_messages: 2,
+        max_len: 30,
+        ..Default::default()
+    };
+    let mut thanks = Thanks::new(config, Duration::from_secs(1));
+    let room = RoomId(1);
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    // 连击按最后更新时间输出,错开时间保证顺序
+    let add = |thanks: &mut Thanks, g: &OneGift, millis: u64| {
+        let now = start + Duration::from_millis(millis);
+        thanks.room(room).combo.add_gift(g, now)
+    };
+
+    add(&mut thanks, &gift(1, "A", "小心心", 4), 1);
+    add(&mut thanks, &gift(1, "A", "小心心", 6), 2);
+    add(&mut thanks, &gift(2, "B", "辣条", 1), 3);
+    thanks.add_guard(
+        room,
+        &GuardBuy {
+            gift_id: 10003,
+            gift_name: "舰长".to_string(),
+            guard_level: 3,
+            num: 1,
+            price: 198000,
+            uid: Uid(3),
+            username: "C".to_string(),
+            start_time: 0,
+        },
+        start,
+    );
+    add(
+        &mut thanks,
+        &gift(4, "一个名字很长很长的用户", "小心心", 1),
+        4,
+    );
+    add(&mut thanks, &gift(5, "E", "小心心", 1), 5);
+
+    // 窗口未结束
+    assert!(thanks.poll(at(2)).is_empty());
+    assert_eq!(
+        thanks.poll(at(10)),
+        vec![(room, "谢谢 C 的 舰长, A 的 10 个小心心".to_string())]
+    );
+    // 发送间隔内不发
+    assert!(thanks.poll(at(12)).is_empty());
+    assert_eq!(
+        thanks.poll(at(15)),
+        vec![(room, "谢谢 其他 3 位的礼物".to_string())]
+    );
+    assert!(thanks.poll(at(30)).is_empty());
+
+    let entries = vec![Entry {
+        uid: Uid(1),
+        uname: "一个名字很长很长很长很长的用户".to_string(),
+        gift_name: "小心心".to_string(),
+        num: 1,
+        guard: false,
+    }];
+    let messages = compose(&entries, &ThanksConfig::default());
+    assert_eq!(
+        messages,
+        vec!["谢谢 一个名字很长很… 的 1 个小心心".to_string()]
+    );
+}
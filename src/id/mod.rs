@@ -0,0 +1,74 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// 定义一个 u64 的编号类型,序列化为数字,反序列化时数字和字符串都接受
+macro_rules! id_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[serde(transparent)]
+        pub struct $name(pub u64);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.trim().parse().map($name)
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(id: u64) -> Self {
+                $name(id)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                #[derive(Deserialize)]
+                #[serde(untagged)]
+                enum NumOrStr {
+                    Num(u64),
+                    Str(String),
+                }
+                match NumOrStr::deserialize(deserializer)? {
+                    NumOrStr::Num(id) => Ok($name(id)),
+                    NumOrStr::Str(s) => s.parse().map_err(serde::de::Error::custom),
+                }
+            }
+        }
+    };
+}
+
+id_type!(
+    /// 用户 uid,游客为 0
+    Uid
+);
+
+id_type!(
+    /// 直播间号
+    RoomId
+);
+
+#[test]
+fn id_serde_test() {
+    let ids: Vec<Uid> = serde_json::from_str(r#"[1, "2", "4294967296"]"#).unwrap();
+    assert_eq!(ids, vec![Uid(1), Uid(2), Uid(4294967296)]);
+    assert_eq!(serde_json::to_string(&ids).unwrap(), "[1,2,4294967296]");
+    assert!(serde_json::from_str::<RoomId>(r#""abc""#).is_err());
+    assert!(serde_json::from_str::<RoomId>("-1").is_err());
+    assert_eq!(
+        "21452505".parse::<RoomId>().unwrap().to_string(),
+        "21452505"
+    );
+}
@@ -0,0 +1,28 @@
+//! b站直播弹幕协议、接口与统计工具。
+//!
+//! - `codec` 特性: 弹幕服务器的二进制包编解码
+//! - `api` 特性: b站 http 接口客户端,以及 `handler` 事件处理器
+//! - `client` 特性: 基于以上两者的直播间 websocket 客户端
+//!
+//! 消息类型、统计、存档与导出不依赖任何特性
+#[macro_use]
+extern crate anyhow;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+pub mod accounting;
+pub mod archive;
+#[cfg(feature = "api")]
+pub mod bili_api;
+pub mod export;
+pub mod guard;
+#[cfg(feature = "api")]
+pub mod handler;
+pub mod id;
+pub mod metrics;
+pub mod proxy;
+pub mod ws;
+
+pub use crate::id::{RoomId, Uid};
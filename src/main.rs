@@ -5,19 +5,260 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-pub mod bili_api;
-pub mod config;
-pub mod task;
-pub mod ws;
+mod config;
+mod task;
+
+use bilili_danmuji_rs::{archive, bili_api, export, guard, metrics, ws, RoomId};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
-    // config::logger_config();
     env_logger::init();
-    let room_id = config::APP_CONFIG.room_id;
-    let api_client = bili_api::get_client().await.unwrap();
-    let ws_client = ws::connect(api_client.clone(), room_id).await;
-    task::run(ws_client, api_client).await;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = run_command(&args) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let room_ids = &config::APP_CONFIG.room_ids;
+    if let Some(addr) = &config::APP_CONFIG.metrics_addr {
+        if let Err(e) = metrics::serve(addr).await {
+            error!("{}", e);
+        }
+    }
+    let proxy = config::APP_CONFIG.proxy.as_ref();
+    let api_client = if config::APP_CONFIG.guest {
+        bili_api::APIClient::guest(proxy)
+    } else {
+        bili_api::get_client(proxy).await
+    };
+    let api_client = match api_client {
+        Ok(api_client) => api_client,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let cancel = CancellationToken::new();
+    tokio::spawn(shutdown_on_signal(cancel.clone()));
+    let mut builder = ws::LiveClientBuilder::new()
+        .rooms(room_ids)
+        .credentials(api_client.clone())
+        .reconnect(config::APP_CONFIG.reconnect.build().into())
+        .liveness_timeout(Duration::from_secs(config::APP_CONFIG.liveness_timeout))
+        .channel_capacity(config::APP_CONFIG.channel_capacity)
+        .backpressure(config::APP_CONFIG.backpressure)
+        .transport(config::APP_CONFIG.transport)
+        .cancel_token(cancel.clone());
+    if let Some(proxy) = config::APP_CONFIG.proxy.clone() {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = config::APP_CONFIG.cmd_catalogue.as_ref() {
+        if let Err(e) = ws::catalogue::open(path) {
+            error!("{}", e);
+        }
+    }
+    if let Some(path) = config::APP_CONFIG.archive.as_ref() {
+        match archive::Recorder::open(path) {
+            Ok(recorder) => builder = builder.recorder(recorder),
+            Err(e) => error!("{}", e),
+        }
+    }
+    let ws_client = match builder.connect().await {
+        Ok(ws_client) => ws_client,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let hub = ws::Hub::new();
+    let task_rx = hub.subscribe(
+        "task",
+        config::APP_CONFIG.channel_capacity,
+        config::APP_CONFIG.backpressure,
+        |_| true,
+    );
+    let hub_handle = hub.run(ws_client.rx);
+    task::run(task_rx, api_client, cancel).await;
+    let _ = hub_handle.await;
+    let _ = tokio::task::spawn_blocking(ws::catalogue::flush).await;
 
     info!("exit")
 }
+
+/// 第一次收到 SIGINT/SIGTERM 时取消 `cancel` 正常退出,第二次直接结束进程
+async fn shutdown_on_signal(cancel: CancellationToken) {
+    wait_signal().await;
+    info!("shutdown, press ctrl-c again to force exit");
+    cancel.cancel();
+    wait_signal().await;
+    warn!("force exit");
+    std::process::exit(130);
+}
+
+#[cfg(unix)]
+async fn wait_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).expect("listen SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+const USAGE: &str = "usage:
+    bilili_danmuji_rs                                         监听直播间
+    bilili_danmuji_rs export-xml <archive> <out.xml> [index]  导出 xml 弹幕
+        [--room <room_id>]
+    bilili_danmuji_rs export-ass <archive> <out.ass> [index]  导出 ass 字幕
+        [--room <room_id>] [--font <name>] [--font-size <n>] [--duration <secs>] [--density <0-1>]
+        [--width <n>] [--height <n>]
+    bilili_danmuji_rs guards <roster.json> [days]              查看舰队与 days 天内到期的舰长
+    bilili_danmuji_rs cmds <catalogue.json> [--sample]         查看未定义的 cmd";
+
+fn run_command(args: &[String]) -> Result<(), anyhow::Error> {
+    match args[0].as_str() {
+        "export-xml" => {
+            let export = parse_export_args(&args[1..])?;
+            let (archive, out) = export.paths()?;
+            let session = export.load_session(archive)?;
+            export::xml::write_xml(&session, out)?;
+            info!("export {} danmu to {}", session.danmu.len(), out);
+            Ok(())
+        }
+        "export-ass" => {
+            let export = parse_export_args(&args[1..])?;
+            let (archive, out) = export.paths()?;
+            let session = export.load_session(archive)?;
+            export::ass::write_ass(&session, &export.ass, out)?;
+            info!("export {} danmu to {}", session.danmu.len(), out);
+            Ok(())
+        }
+        "guards" => {
+            let path = args.get(1).ok_or(anyhow!("{}", USAGE))?;
+            let days = match args.get(2) {
+                Some(days) => days
+                    .parse()
+                    .map_err(|e| anyhow!("bad days {} {}", days, e))?,
+                None => 7,
+            };
+            let roster = guard::GuardRoster::load(path)?;
+            let now = guard::now_secs();
+            println!("当前舰队:");
+            for m in roster.current(now) {
+                print_guard(m, now);
+            }
+            println!("{}天内到期:", days);
+            for m in roster.expiring_within(now, days) {
+                print_guard(m, now);
+            }
+            Ok(())
+        }
+        "cmds" => {
+            let path = args.get(1).ok_or(anyhow!("{}", USAGE))?;
+            let sample = args.get(2).map(|a| a == "--sample").unwrap_or(false);
+            let catalogue = ws::CmdCatalogue::load(path)?;
+            let now = guard::now_secs();
+            for entry in catalogue.sorted() {
+                println!(
+                    "  {} 共{}次 {}天前首次收到",
+                    entry.cmd,
+                    entry.count,
+                    now.saturating_sub(entry.first_seen) / guard::DAY_SECS
+                );
+                if sample {
+                    println!("    {}", entry.sample);
+                }
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("{}", USAGE)),
+    }
+}
+
+struct ExportArgs<'a> {
+    positional: Vec<&'a String>,
+    room_id: Option<RoomId>,
+    ass: export::ass::AssOptions,
+}
+
+impl<'a> ExportArgs<'a> {
+    fn paths(&self) -> Result<(&'a str, &'a str), anyhow::Error> {
+        match self.positional.as_slice() {
+            [archive, out, ..] => Ok((archive.as_str(), out.as_str())),
+            _ => Err(anyhow!("{}", USAGE)),
+        }
+    }
+
+    /// 读取存档中的第 `index` 场直播,默认最后一场
+    fn load_session(&self, archive: &str) -> Result<export::LiveSession, anyhow::Error> {
+        let mut records = archive::read_archive(archive)?;
+        if let Some(room_id) = self.room_id {
+            records.retain(|r| r.room_id == room_id);
+        }
+        let mut sessions = export::split_sessions(records);
+        if sessions.is_empty() {
+            return Err(anyhow!("no live session in {}", archive));
+        }
+        let index = match self.positional.get(2) {
+            Some(i) => i.parse().map_err(|e| anyhow!("bad index {} {}", i, e))?,
+            None => sessions.len() - 1,
+        };
+        if index >= sessions.len() {
+            return Err(anyhow!(
+                "index {} out of {} sessions",
+                index,
+                sessions.len()
+            ));
+        }
+        Ok(sessions.swap_remove(index))
+    }
+}
+
+fn parse_export_args(args: &[String]) -> Result<ExportArgs<'_>, anyhow::Error> {
+    fn value<T: std::str::FromStr>(name: &str, v: Option<&String>) -> Result<T, anyhow::Error> {
+        v.and_then(|v| v.parse().ok())
+            .ok_or(anyhow!("bad value for {}", name))
+    }
+
+    let mut export = ExportArgs {
+        positional: vec![],
+        room_id: None,
+        ass: export::ass::AssOptions::default(),
+    };
+    let opt = &mut export.ass;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--room" => export.room_id = Some(value("--room", args.next())?),
+            "--font" => opt.font_name = value("--font", args.next())?,
+            "--font-size" => opt.font_size = value("--font-size", args.next())?,
+            "--duration" => opt.duration = value("--duration", args.next())?,
+            "--density" => opt.density = value("--density", args.next())?,
+            "--width" => opt.width = value("--width", args.next())?,
+            "--height" => opt.height = value("--height", args.next())?,
+            _ => export.positional.push(arg),
+        }
+    }
+    Ok(export)
+}
+
+fn print_guard(m: &guard::GuardMember, now: u64) {
+    println!(
+        "  {} {}({}) 剩余{}天",
+        guard::guard_name(m.guard_level),
+        m.username,
+        m.uid,
+        (m.expire_time - now) / guard::DAY_SECS
+    );
+}
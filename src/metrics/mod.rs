@@ -0,0 +1,185 @@
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+}
+
+pub const NOTIFICATIONS: Metric = Metric {
+    name: "danmuji_notifications_total",
+    help: "收到的通知消息数,按 cmd 区分,未定义的 cmd 计入 unknown",
+    kind: MetricKind::Counter,
+};
+pub const DECODE_ERRORS: Metric = Metric {
+    name: "danmuji_decode_errors_total",
+    help: "解码失败次数,按 MsgDecodeError 区分",
+    kind: MetricKind::Counter,
+};
+pub const RECONNECTS: Metric = Metric {
+    name: "danmuji_reconnects_total",
+    help: "重连次数,按直播间和断开原因区分",
+    kind: MetricKind::Counter,
+};
+pub const CONNECTED: Metric = Metric {
+    name: "danmuji_connected",
+    help: "直播间是否已连接",
+    kind: MetricKind::Gauge,
+};
+pub const CHANNEL_DEPTH: Metric = Metric {
+    name: "danmuji_channel_depth",
+    help: "消息队列中等待处理的消息数",
+    kind: MetricKind::Gauge,
+};
+pub const API_REQUESTS: Metric = Metric {
+    name: "danmuji_api_requests_total",
+    help: "http 接口调用次数,按接口和返回的 code 区分",
+    kind: MetricKind::Counter,
+};
+pub const HEARTBEAT_RTT: Metric = Metric {
+    name: "danmuji_heartbeat_rtt_seconds",
+    help: "最近一次心跳的往返时间",
+    kind: MetricKind::Gauge,
+};
+
+struct Family {
+    help: &'static str,
+    kind: MetricKind,
+    /// 渲染好的标签 -> 值
+    samples: BTreeMap<String, f64>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+}
+
+fn update(metric: &Metric, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+    let mut rendered = String::new();
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            rendered.push(',');
+        }
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(rendered, "{}=\"{}\"", k, v);
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(metric.name).or_insert_with(|| Family {
+        help: metric.help,
+        kind: metric.kind,
+        samples: BTreeMap::new(),
+    });
+    f(family.samples.entry(rendered).or_insert(0.0));
+}
+
+pub fn inc(metric: &Metric, labels: &[(&str, &str)]) {
+    update(metric, labels, |v| *v += 1.0);
+}
+
+pub fn set(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    update(metric, labels, |v| *v = value);
+}
+
+/// prometheus 文本格式
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in family.samples.iter() {
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+    out
+}
+
+/// 在 `addr` 上提供 `GET /metrics`
+pub async fn serve(addr: &str) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("metrics bind {} {}", addr, e))?;
+    info!("metrics listen on {}", addr);
+    Ok(serve_listener(listener))
+}
+
+pub fn serve_listener(listener: TcpListener) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream).await {
+                            debug!("metrics request {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("metrics accept {}", e),
+            }
+        }
+    })
+}
+
+async fn handle(mut stream: TcpStream) -> Result<(), Error> {
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let req = String::from_utf8_lossy(&buf[..n]);
+    let path = req.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_test() {
+    inc(&NOTIFICATIONS, &[("cmd", "metrics_test")]);
+    inc(&NOTIFICATIONS, &[("cmd", "metrics_test")]);
+    set(&HEARTBEAT_RTT, &[("room", "metrics\"test")], 0.25);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    serve_listener(listener);
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut text = String::new();
+    stream.read_to_string(&mut text).await.unwrap();
+    assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(text.contains("# TYPE danmuji_notifications_total counter\n"));
+    assert!(text.contains("danmuji_notifications_total{cmd=\"metrics_test\"} 2\n"));
+    assert!(text.contains("danmuji_heartbeat_rtt_seconds{room=\"metrics\\\"test\"} 0.25\n"));
+}
@@ -0,0 +1,79 @@
+use bilili_danmuji_rs::handler::{Context, EventHandler, HandlerResult};
+use bilili_danmuji_rs::ws::message::notification_msg::{DanmuMsg, EntryEffect, Interact};
+use bilili_danmuji_rs::ws::ConnectionState;
+use bilili_danmuji_rs::RoomId;
+
+/// 把收到的事件打印到日志
+pub struct LogHandler;
+
+impl EventHandler for LogHandler {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn on_connection<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        state: &'a ConnectionState,
+    ) -> HandlerResult<'a> {
+        info!("[{}] 连接状态: {:?}", room_id, state);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_live<'a>(&'a mut self, _ctx: &'a Context, room_id: RoomId) -> HandlerResult<'a> {
+        info!("[{}] 直播开始", room_id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_preparing<'a>(&'a mut self, _ctx: &'a Context, room_id: RoomId) -> HandlerResult<'a> {
+        info!("[{}] 直播结束", room_id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_danmu<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        msg: &'a DanmuMsg,
+    ) -> HandlerResult<'a> {
+        info!("[{}] 弹幕: {:?}", room_id, msg);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_interact<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        data: &'a Interact,
+    ) -> HandlerResult<'a> {
+        match data.msg_type {
+            1 => {
+                info!("[{}] 进入直播间: {:?}", room_id, data);
+            }
+            2 => {
+                info!("[{}] 关注直播间: {:?}", room_id, data);
+            }
+            3 => {
+                info!("[{}] 分享直播间: {:?}", room_id, data);
+            }
+            5 => {
+                info!("[{}] 互关: {:?}", room_id, data);
+            }
+            _ => {
+                warn!("[{}] 未知: {:?}", room_id, data);
+            }
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_entry_effect<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        data: &'a EntryEffect,
+    ) -> HandlerResult<'a> {
+        info!("[{}] 舰长进入直播间: {:?}", room_id, data);
+        Box::pin(async { Ok(()) })
+    }
+}
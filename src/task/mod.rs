@@ -1,58 +1,49 @@
-use crate::bili_api::APIClient;
-use crate::ws::{MsgStream, NotificationMsg, ServerLiveMessage};
+mod logging;
+mod stats;
 
-pub async fn run(mut ws_client: MsgStream, _api_client: APIClient) {
-    while let Some(recv_msg) = ws_client.rx.recv().await {
-        match recv_msg {
-            ServerLiveMessage::LoginAck => {
-                debug!("login ack")
-            }
-            ServerLiveMessage::Notification(notification) => match notification {
-                NotificationMsg::LIVE { .. } => {
-                    info!("直播开始");
-                }
-                NotificationMsg::DANMU_MSG { info: msg }
-                | NotificationMsg::DANMU_MSG_N { info: msg } => {
-                    info!("弹幕: {:?}", msg);
-                }
-                NotificationMsg::ENTRY_EFFECT { data } => {
-                    info!("舰长进入直播间: {:?}", data);
-                }
-                NotificationMsg::INTERACT_WORD { data } => match data.msg_type {
-                    1 => {
-                        info!("进入直播间: {:?}", data);
-                    }
-                    2 => {
-                        info!("关注直播间: {:?}", data);
-                    }
-                    3 => {
-                        info!("分享直播间: {:?}", data);
-                    }
-                    5 => {
-                        info!("互关: {:?}", data);
-                    }
-                    _ => {
-                        warn!("未知: {:?}", data);
-                    }
-                },
-                NotificationMsg::ENTRY_EFFECT_MUST_RECEIVE { .. } => {}
-                NotificationMsg::NOTICE_MSG { .. } => {}
-                NotificationMsg::STOP_LIVE_ROOM_LIST { .. } => {}
-                NotificationMsg::SEND_GIFT { data: gift } => {
-                    info!("礼物: {:?}", gift);
-                }
-                NotificationMsg::COMBO_SEND { data: gift } => {
-                    info!("礼物连击: {:?}", gift);
-                }
-                NotificationMsg::GUARD_BUY { data: guard_buy } => {
-                    info!("购买大航海: {:?}", guard_buy);
-                }
-                _ => {}
-            },
-            ServerLiveMessage::ServerHeartBeat => {
-                debug!("heart_beat")
+use crate::config::APP_CONFIG;
+use bilili_danmuji_rs::bili_api::APIClient;
+use bilili_danmuji_rs::handler::reply::AutoReply;
+use bilili_danmuji_rs::handler::thanks::Thanks;
+use bilili_danmuji_rs::handler::Pipeline;
+use bilili_danmuji_rs::ws::{MsgReceiver, RoomMessage};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// `rx` 是从 `Hub` 订阅的消息。`cancel` 取消后继续接收消息,直到所有连接关闭,再输出统计
+pub async fn run(
+    rx: MsgReceiver<Arc<RoomMessage>>,
+    api_client: APIClient,
+    cancel: CancellationToken,
+) {
+    let send_interval = Duration::from_millis(APP_CONFIG.send_interval_ms);
+    let mut pipeline = Pipeline::new(api_client.clone(), send_interval)
+        .register(0, logging::LogHandler)
+        .register(10, stats::StatsHandler::default());
+    if !APP_CONFIG.auto_reply.rules.is_empty() {
+        if api_client.is_guest() {
+            warn!("guest mode, auto reply disabled");
+        } else {
+            match AutoReply::new(&APP_CONFIG.auto_reply) {
+                Ok(auto_reply) => pipeline = pipeline.register(20, auto_reply),
+                Err(e) => error!("{}", e),
             }
         }
     }
-    warn!("ws client recv none,loop stop")
+    if APP_CONFIG.thanks.enabled {
+        if api_client.is_guest() {
+            warn!("guest mode, thanks disabled");
+        } else {
+            let combo_timeout = Duration::from_secs(APP_CONFIG.combo_timeout);
+            let thanks = Thanks::new(APP_CONFIG.thanks.clone(), combo_timeout);
+            pipeline = pipeline.register(30, thanks);
+        }
+    }
+    pipeline.run(rx).await;
+    if cancel.is_cancelled() {
+        info!("all client closed, loop stop")
+    } else {
+        warn!("ws client recv none,loop stop")
+    }
 }
@@ -0,0 +1,127 @@
+use crate::config::{room_path, APP_CONFIG};
+use bilili_danmuji_rs::accounting::combo::ComboAggregator;
+use bilili_danmuji_rs::accounting::Accounting;
+use bilili_danmuji_rs::guard::{now_secs, GuardRoster};
+use bilili_danmuji_rs::handler::{Context, EventHandler, HandlerResult};
+use bilili_danmuji_rs::ws::message::notification_msg::{BatchGift, GuardBuy, OneGift};
+use bilili_danmuji_rs::ws::NotificationMsg;
+use bilili_danmuji_rs::RoomId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// 每个直播间各自的统计
+struct RoomState {
+    accounting: Accounting,
+    roster: Option<GuardRoster>,
+    combo: ComboAggregator,
+}
+
+impl RoomState {
+    fn new(room_id: RoomId) -> Self {
+        let roster = APP_CONFIG.guard_roster.as_ref().and_then(|path| {
+            match GuardRoster::load(room_path(path, room_id).as_str()) {
+                Ok(roster) => Some(roster),
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            }
+        });
+        RoomState {
+            accounting: Accounting::new(room_id, APP_CONFIG.gift_report_dir.clone()),
+            roster,
+            combo: ComboAggregator::new(Duration::from_secs(APP_CONFIG.combo_timeout)),
+        }
+    }
+}
+
+/// 礼物统计、连击合并和舰队名单
+#[derive(Default)]
+pub struct StatsHandler {
+    rooms: HashMap<RoomId, RoomState>,
+}
+
+impl StatsHandler {
+    fn room(&mut self, room_id: RoomId) -> &mut RoomState {
+        self.rooms
+            .entry(room_id)
+            .or_insert_with(|| RoomState::new(room_id))
+    }
+}
+
+impl EventHandler for StatsHandler {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn on_notification<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        msg: &'a NotificationMsg,
+    ) -> HandlerResult<'a> {
+        self.room(room_id).accounting.handle(msg);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_gift<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        gift: &'a OneGift,
+    ) -> HandlerResult<'a> {
+        debug!("[{}] 礼物: {:?}", room_id, gift);
+        self.room(room_id).combo.add_gift(gift, Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_combo<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        gift: &'a BatchGift,
+    ) -> HandlerResult<'a> {
+        debug!("[{}] 礼物连击: {:?}", room_id, gift);
+        self.room(room_id).combo.add_combo(gift, Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_guard_buy<'a>(
+        &'a mut self,
+        _ctx: &'a Context,
+        room_id: RoomId,
+        guard_buy: &'a GuardBuy,
+    ) -> HandlerResult<'a> {
+        info!("[{}] 购买大航海: {:?}", room_id, guard_buy);
+        let r = match self.room(room_id).roster.as_mut() {
+            Some(roster) => {
+                let purchase = roster.add(guard_buy, now_secs());
+                if purchase.renewal {
+                    info!("[{}] 续费大航海: {}", room_id, purchase.username);
+                }
+                roster.save()
+            }
+            None => Ok(()),
+        };
+        Box::pin(async { r })
+    }
+
+    fn on_tick<'a>(&'a mut self, _ctx: &'a Context, now: Instant) -> HandlerResult<'a> {
+        for (room_id, room) in self.rooms.iter_mut() {
+            for gift in room.combo.poll(now) {
+                info!("[{}] 礼物: {:?}", room_id, gift);
+            }
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_shutdown<'a>(&'a mut self, _ctx: &'a Context) -> HandlerResult<'a> {
+        for (room_id, room) in self.rooms.iter_mut() {
+            for gift in room.combo.flush() {
+                info!("[{}] 礼物: {:?}", room_id, gift);
+            }
+            room.accounting.finish();
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -0,0 +1,180 @@
+use crate::archive::{ArchiveWriter, Recorder};
+use crate::bili_api::APIClient;
+use crate::id::RoomId;
+use crate::proxy::Proxy;
+use crate::ws::channel::BackpressurePolicy;
+use crate::ws::client::{connect, ConnectOptions, MsgStream, Transport};
+use crate::ws::reconnect::ReconnectPolicy;
+use anyhow::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+/// 组装一个直播间客户端
+///
+/// ```no_run
+/// # async fn run() -> Result<(), anyhow::Error> {
+/// use bilili_danmuji_rs::ws::LiveClientBuilder;
+/// use bilili_danmuji_rs::RoomId;
+///
+/// let mut stream = LiveClientBuilder::new()
+///     .room(RoomId(21452505))
+///     .connect()
+///     .await?;
+/// while let Some(msg) = stream.rx.recv().await {
+///     println!("{:?}", msg);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct LiveClientBuilder {
+    room_ids: Vec<RoomId>,
+    credentials: Option<APIClient>,
+    guest: bool,
+    options: ConnectOptions,
+    recorder: Option<Recorder>,
+    cancel: Option<CancellationToken>,
+}
+
+impl LiveClientBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 可以多次调用监听多个直播间
+    pub fn room(mut self, room_id: RoomId) -> Self {
+        if !self.room_ids.contains(&room_id) {
+            self.room_ids.push(room_id);
+        }
+        self
+    }
+
+    pub fn rooms(mut self, room_ids: &[RoomId]) -> Self {
+        for &room_id in room_ids {
+            self = self.room(room_id);
+        }
+        self
+    }
+
+    /// 不设置时 `connect` 调用 `bili_api::get_client`,可能需要扫码登录
+    pub fn credentials(mut self, api_client: APIClient) -> Self {
+        self.credentials = Some(api_client);
+        self
+    }
+
+    /// 没有设置 `credentials` 时以游客身份连接,不需要登录,但弹幕用户名会被打码
+    pub fn guest(mut self, guest: bool) -> Self {
+        self.guest = guest;
+        self
+    }
+
+    pub fn endpoint(mut self, url: Url) -> Self {
+        self.options.endpoint = Some(url);
+        self
+    }
+
+    /// 支持 1(不压缩) 和 2(zlib),默认为 2
+    pub fn protover(mut self, protover: u32) -> Self {
+        self.options.protover = protover;
+        self
+    }
+
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.options.channel_capacity = capacity;
+        self
+    }
+
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.options.backpressure = policy;
+        self
+    }
+
+    pub fn reconnect(mut self, policy: Arc<dyn ReconnectPolicy>) -> Self {
+        self.options.reconnect = policy;
+        self
+    }
+
+    pub fn liveness_timeout(mut self, timeout: Duration) -> Self {
+        self.options.liveness_timeout = timeout;
+        self
+    }
+
+    /// 默认为 websocket
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.options.transport = transport;
+        self
+    }
+
+    /// 弹幕服务器连接使用这个代理,没有设置 `credentials` 时创建的 `APIClient` 也使用它
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
+
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// 不设置时可以用 `MsgStream.rooms.shutdown()` 关闭
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
+    pub async fn connect(mut self) -> Result<MsgStream, Error> {
+        if !(1..=2).contains(&self.options.protover) {
+            return Err(anyhow!("protover {} not supported", self.options.protover));
+        }
+        if self.options.channel_capacity == 0 {
+            return Err(anyhow!("channel capacity must be greater than 0"));
+        }
+        let api_client = match self.credentials {
+            Some(api_client) => api_client,
+            None if self.guest => APIClient::guest(self.options.proxy.as_ref())?,
+            None => crate::bili_api::get_client(self.options.proxy.as_ref()).await?,
+        };
+        // 存档积压的上限和处理方式与消息队列相同
+        if let Some(recorder) = self.recorder {
+            let capacity = self.options.channel_capacity;
+            let policy = self.options.backpressure;
+            self.options.recorder = Some(ArchiveWriter::spawn(recorder, capacity, policy));
+        }
+        let cancel = self.cancel.unwrap_or_default();
+        Ok(connect(api_client, &self.room_ids, self.options, cancel).await)
+    }
+}
+
+#[tokio::test]
+async fn builder_test() {
+    use crate::ws::reconnect::Unlimited;
+
+    let builder = LiveClientBuilder::new()
+        .rooms(&[RoomId(1), RoomId(2)])
+        .room(RoomId(1))
+        .protover(1)
+        .channel_capacity(10)
+        .reconnect(Arc::new(Unlimited {
+            delay: Duration::from_secs(1),
+        }))
+        .endpoint("wss://example.com/sub".parse().unwrap());
+    assert_eq!(builder.room_ids, vec![RoomId(1), RoomId(2)]);
+    assert_eq!(builder.options().protover, 1);
+    assert_eq!(
+        builder.options().reconnect.next_delay(100),
+        Some(Duration::from_secs(1))
+    );
+
+    let r = LiveClientBuilder::new()
+        .room(RoomId(1))
+        .protover(3)
+        .connect()
+        .await;
+    assert!(r.is_err());
+}
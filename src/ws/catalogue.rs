@@ -0,0 +1,196 @@
+use crate::guard::now_secs;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+/// 距上次保存超过这个秒数才再次写文件,新出现的 cmd 总是立即保存
+const SAVE_INTERVAL_SECS: u64 = 60;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CmdEntry {
+    pub cmd: String,
+    /// 第一次收到的时间 秒
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub count: u64,
+    /// 第一次收到时的完整消息
+    pub sample: Value,
+}
+
+/// `NotificationMsg` 中还没有定义的 cmd,用来发现协议的新消息
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct CmdCatalogue {
+    pub entries: BTreeMap<String, CmdEntry>,
+    #[serde(skip)]
+    path: Option<String>,
+    #[serde(skip)]
+    last_save: u64,
+}
+
+lazy_static! {
+    static ref CATALOGUE: Mutex<CmdCatalogue> = Mutex::new(CmdCatalogue::default());
+    /// 通知写文件线程保存,`open` 之前为空
+    static ref SAVER: Mutex<Option<SyncSender<()>>> = Mutex::new(None);
+    /// 保证后取的快照不会被先取的快照覆盖
+    static ref WRITING: Mutex<()> = Mutex::new(());
+}
+
+impl CmdCatalogue {
+    /// 文件不存在时返回空目录
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut catalogue = match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str::<CmdCatalogue>(s.as_str())
+                .map_err(|e| anyhow!("parse {} {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => CmdCatalogue::default(),
+            Err(e) => return Err(anyhow!("read {} {}", path, e)),
+        };
+        catalogue.path = Some(path.to_string());
+        Ok(catalogue)
+    }
+
+    /// 没有设置路径时只保存在内存中
+    pub fn save(&mut self, now: u64) -> Result<(), Error> {
+        if let Some((path, json)) = self.snapshot()? {
+            write(&path, json)?;
+        }
+        self.last_save = now;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Option<(String, String)>, Error> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+        let json = serde_json::to_string_pretty(self).map_err(|e| anyhow!("{}", e))?;
+        Ok(Some((path, json)))
+    }
+
+    /// 返回是否是第一次见到这个 cmd
+    pub fn record(&mut self, cmd: &str, body: &Value, now: u64) -> bool {
+        if let Some(entry) = self.entries.get_mut(cmd) {
+            entry.count += 1;
+            entry.last_seen = now;
+            return false;
+        }
+        self.entries.insert(
+            cmd.to_string(),
+            CmdEntry {
+                cmd: cmd.to_string(),
+                first_seen: now,
+                last_seen: now,
+                count: 1,
+                sample: body.clone(),
+            },
+        );
+        true
+    }
+
+    /// 按次数从多到少
+    pub fn sorted(&self) -> Vec<&CmdEntry> {
+        let mut entries: Vec<&CmdEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then(a.cmd.cmp(&b.cmd)));
+        entries
+    }
+}
+
+fn write(path: &str, json: String) -> Result<(), Error> {
+    std::fs::write(path, json).map_err(|e| anyhow!("write {} {}", path, e))
+}
+
+/// 在锁外写文件,只在取快照时短暂持有 `CATALOGUE`
+fn save_snapshot() {
+    let _writing = WRITING.lock().unwrap();
+    let snapshot = CATALOGUE.lock().unwrap().snapshot();
+    let r = match snapshot {
+        Ok(Some((path, json))) => write(&path, json),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = r {
+        warn!("save cmd catalogue {}", e);
+    }
+}
+
+/// 设置全局目录的保存路径,读入已有的记录,并启动写文件的线程
+pub fn open(path: &str) -> Result<(), Error> {
+    let catalogue = CmdCatalogue::load(path)?;
+    info!(
+        "cmd catalogue {} has {} entries",
+        path,
+        catalogue.entries.len()
+    );
+    *CATALOGUE.lock().unwrap() = catalogue;
+    let mut saver = SAVER.lock().unwrap();
+    if saver.is_none() {
+        // 容量为 1,已经有一次保存在排队时新的请求可以合并进去
+        let (tx, rx) = sync_channel(1);
+        std::thread::Builder::new()
+            .name("cmd-catalogue".to_string())
+            .spawn(move || {
+                for () in rx {
+                    save_snapshot();
+                }
+            })
+            .map_err(|e| anyhow!("spawn cmd catalogue thread {}", e))?;
+        *saver = Some(tx);
+    }
+    Ok(())
+}
+
+/// 解码时遇到未定义的 cmd 调用,只更新内存,文件由单独的线程写入
+pub fn record(cmd: &str, body: &Value) {
+    let now = now_secs();
+    let due = {
+        let mut catalogue = CATALOGUE.lock().unwrap();
+        let new = catalogue.record(cmd, body, now);
+        if new {
+            info!("new cmd {}", cmd);
+        }
+        let due = new || now >= catalogue.last_save + SAVE_INTERVAL_SECS;
+        if due {
+            catalogue.last_save = now;
+        }
+        due
+    };
+    if due {
+        if let Some(saver) = SAVER.lock().unwrap().as_ref() {
+            match saver.try_send(()) {
+                Ok(()) | Err(TrySendError::Full(())) => {}
+                Err(TrySendError::Disconnected(())) => warn!("cmd catalogue thread stopped"),
+            }
+        }
+    }
+}
+
+/// 把还没保存的计数写入文件,会阻塞到写完
+pub fn flush() {
+    save_snapshot();
+}
+
+#[test]
+fn catalogue_test() {
+    let path = std::env::temp_dir().join(format!("cmd_catalogue_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut catalogue = CmdCatalogue::load(path).unwrap();
+    let body = serde_json::json!({"cmd": "NEW_CMD", "data": {"a": 1}});
+    assert!(catalogue.record("NEW_CMD", &body, 10));
+    assert!(!catalogue.record("NEW_CMD", &serde_json::json!({}), 20));
+    assert!(catalogue.record("OTHER_CMD", &serde_json::json!({}), 30));
+    catalogue.save(30).unwrap();
+
+    let catalogue = CmdCatalogue::load(path).unwrap();
+    let _ = std::fs::remove_file(path);
+    let sorted: Vec<(&str, u64)> = catalogue
+        .sorted()
+        .iter()
+        .map(|e| (e.cmd.as_str(), e.count))
+        .collect();
+    assert_eq!(sorted, vec![("NEW_CMD", 2), ("OTHER_CMD", 1)]);
+    let entry = &catalogue.entries["NEW_CMD"];
+    assert_eq!((entry.first_seen, entry.last_seen), (10, 20));
+    assert_eq!(entry.sample, body);
+}
@@ -0,0 +1,332 @@
+use crate::ws::{NotificationMsg, RoomMessage, ServerLiveMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+
+/// 队列满时的处理方式
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// 等待消费者,会阻塞读取 socket
+    #[default]
+    Block,
+    /// 丢弃最早的消息
+    DropOldest,
+    /// 优先丢弃进场、排行榜之类的消息,保留弹幕和礼物
+    DropLowPriority,
+    /// 不限制长度
+    Unbounded,
+}
+
+/// 数值越小越先被丢弃
+pub trait Priority {
+    fn priority(&self) -> u8;
+}
+
+impl Priority for ServerLiveMessage {
+    fn priority(&self) -> u8 {
+        match self {
+            ServerLiveMessage::Connection(_) | ServerLiveMessage::LoginAck => 3,
+            ServerLiveMessage::ServerHeartBeat => 0,
+            ServerLiveMessage::Notification(notification) => match notification {
+                NotificationMsg::LIVE { .. }
+                | NotificationMsg::PREPARING {}
+                | NotificationMsg::SEND_GIFT { .. }
+                | NotificationMsg::COMBO_SEND { .. }
+                | NotificationMsg::GUARD_BUY { .. } => 3,
+                NotificationMsg::DANMU_MSG { .. } | NotificationMsg::DANMU_MSG_N { .. } => 2,
+                NotificationMsg::INTERACT_WORD { .. }
+                | NotificationMsg::ENTRY_EFFECT { .. }
+                | NotificationMsg::ENTRY_EFFECT_MUST_RECEIVE {}
+                | NotificationMsg::ONLINE_RANK_COUNT {}
+                | NotificationMsg::ONLINE_RANK_TOP3 {}
+                | NotificationMsg::ONLINE_RANK_V2 {}
+                | NotificationMsg::WATCHED_CHANGE {}
+                | NotificationMsg::LIKE_INFO_V3_UPDATE {}
+                | NotificationMsg::LIKE_INFO_V3_CLICK {}
+                | NotificationMsg::ROOM_REAL_TIME_MESSAGE_UPDATE {} => 0,
+                _ => 1,
+            },
+        }
+    }
+}
+
+impl Priority for RoomMessage {
+    fn priority(&self) -> u8 {
+        self.msg.priority()
+    }
+}
+
+impl<T: Priority> Priority for Arc<T> {
+    fn priority(&self) -> u8 {
+        self.as_ref().priority()
+    }
+}
+
+struct State<T> {
+    buf: VecDeque<T>,
+    senders: usize,
+    rx_closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Notify,
+    /// 只在 `Block` 时使用,许可数即剩余容量
+    permits: Semaphore,
+    capacity: usize,
+    policy: BackpressurePolicy,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// 只有 `Block` 会返回
+    Full(T),
+    Closed(T),
+}
+
+pub struct MsgSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct MsgReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// 按 `policy` 处理队列满的消息通道
+pub fn channel<T: Priority>(
+    capacity: usize,
+    policy: BackpressurePolicy,
+) -> (MsgSender<T>, MsgReceiver<T>) {
+    let capacity = capacity.max(1);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buf: VecDeque::new(),
+            senders: 1,
+            rx_closed: false,
+        }),
+        not_empty: Notify::new(),
+        permits: Semaphore::new(capacity),
+        capacity,
+        policy,
+        dropped: AtomicU64::new(0),
+    });
+    (
+        MsgSender {
+            shared: shared.clone(),
+        },
+        MsgReceiver { shared },
+    )
+}
+
+impl<T: Priority> MsgSender<T> {
+    /// 接收端已关闭时返回原消息
+    pub async fn send(&self, msg: T) -> Result<(), T> {
+        if self.shared.policy == BackpressurePolicy::Block {
+            match self.shared.permits.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return Err(msg),
+            }
+        }
+        self.push(msg)
+    }
+
+    /// 不等待,`Block` 队列满时返回 `Full`
+    pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        if self.shared.policy == BackpressurePolicy::Block {
+            match self.shared.permits.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(TryAcquireError::NoPermits) => {
+                    self.count_drop();
+                    return Err(TrySendError::Full(msg));
+                }
+                Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(msg)),
+            }
+        }
+        self.push(msg).map_err(TrySendError::Closed)
+    }
+
+    fn push(&self, msg: T) -> Result<(), T> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.rx_closed {
+            return Err(msg);
+        }
+        let full = state.buf.len() >= shared.capacity;
+        match shared.policy {
+            BackpressurePolicy::Block | BackpressurePolicy::Unbounded => state.buf.push_back(msg),
+            BackpressurePolicy::DropOldest => {
+                if full {
+                    state.buf.pop_front();
+                    self.count_drop();
+                }
+                state.buf.push_back(msg);
+            }
+            BackpressurePolicy::DropLowPriority => {
+                if full {
+                    // 丢弃优先级最低的消息中最早的一条,新消息优先级最低时丢弃新消息
+                    let lowest = state
+                        .buf
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(i, m)| (m.priority(), *i))
+                        .map(|(i, m)| (i, m.priority()));
+                    self.count_drop();
+                    match lowest {
+                        Some((i, p)) if p <= msg.priority() => {
+                            state.buf.remove(i);
+                            state.buf.push_back(msg);
+                        }
+                        _ => {}
+                    }
+                } else {
+                    state.buf.push_back(msg);
+                }
+            }
+        }
+        drop(state);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn count_drop(&self) {
+        let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped % 100 == 1 {
+            warn!("message queue full, dropped {} messages", dropped);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().rx_closed
+    }
+
+    /// 队列中还没有被取走的消息数
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for MsgSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        MsgSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for MsgSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.not_empty.notify_one();
+        }
+    }
+}
+
+impl<T> MsgReceiver<T> {
+    /// 所有发送端都已关闭且队列为空时返回 `None`
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(msg) = state.buf.pop_front() {
+                    drop(state);
+                    self.release();
+                    return Some(msg);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            self.shared.not_empty.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let msg = self.shared.state.lock().unwrap().buf.pop_front();
+        if msg.is_some() {
+            self.release();
+        }
+        msg
+    }
+
+    fn release(&self) {
+        if self.shared.policy == BackpressurePolicy::Block {
+            self.shared.permits.add_permits(1);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 因队列满被丢弃的消息数,包括 `try_send` 时队列满
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for MsgReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().rx_closed = true;
+        self.shared.permits.close();
+    }
+}
+
+#[cfg(test)]
+impl Priority for u8 {
+    fn priority(&self) -> u8 {
+        *self
+    }
+}
+
+#[tokio::test]
+async fn drop_policy_test() {
+    let (wx, mut rx) = channel::<u8>(3, BackpressurePolicy::DropOldest);
+    for i in 0..5 {
+        wx.send(i).await.unwrap();
+    }
+    assert_eq!(rx.dropped(), 2);
+    assert_eq!(rx.recv().await, Some(2));
+
+    let (wx, mut rx) = channel::<u8>(3, BackpressurePolicy::DropLowPriority);
+    for p in [2, 0, 1, 3, 0] {
+        wx.send(p).await.unwrap();
+    }
+    assert_eq!(rx.dropped(), 2);
+    drop(wx);
+    let mut left = vec![];
+    while let Some(p) = rx.recv().await {
+        left.push(p);
+    }
+    assert_eq!(left, vec![2, 1, 3]);
+}
+
+#[tokio::test]
+async fn block_policy_test() {
+    let (wx, mut rx) = channel::<u8>(1, BackpressurePolicy::Block);
+    wx.send(1).await.unwrap();
+    let blocked = tokio::spawn(async move {
+        wx.send(2).await.unwrap();
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert_eq!(rx.len(), 1);
+    assert_eq!(rx.recv().await, Some(1));
+    blocked.await.unwrap();
+    assert_eq!(rx.recv().await, Some(2));
+    assert_eq!(rx.recv().await, None);
+}
@@ -0,0 +1,833 @@
+use crate::archive::ArchiveWriter;
+use crate::bili_api::{APIClient, APIResult, LiveHost};
+use crate::id::{RoomId, Uid};
+use crate::metrics;
+use crate::proxy::Proxy;
+use crate::ws::channel::{self, BackpressurePolicy, MsgReceiver, MsgSender};
+use crate::ws::codec::{decode_from_server, MsgDecodeError};
+use crate::ws::message::{ClientLiveMessage, ConnectionState, ServerLiveMessage, WsLogin};
+use crate::ws::reconnect::{ReconnectConfig, ReconnectPolicy};
+use crate::ws::tcp::{read_package, tcp_hosts};
+use crate::ws::RoomMessage;
+use anyhow::Error;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, LinkedList};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error as ThisError;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls, connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+/// 连接弹幕服务器的方式
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// wss
+    #[default]
+    Websocket,
+    /// 直接使用 tcp 端口,没有 tls 和 websocket 的开销,适合同时连接大量直播间
+    Tcp,
+}
+
+#[derive(Clone)]
+pub struct ConnectOptions {
+    pub reconnect: Arc<dyn ReconnectPolicy>,
+    /// 超过这个时间没有收到任何消息(包括心跳回应)就断开重连
+    pub liveness_timeout: Duration,
+    pub channel_capacity: usize,
+    pub backpressure: BackpressurePolicy,
+    /// 指定弹幕服务器地址,不使用 `getDanmuInfo` 返回的服务器列表
+    pub endpoint: Option<Url>,
+    /// 登录包中的协议版本,1 为不压缩,2 为 zlib 压缩
+    pub protover: u32,
+    /// 收到的通知消息在进入消息队列前交给存档线程写入
+    pub recorder: Option<ArchiveWriter>,
+    /// 弹幕服务器通过代理连接,http 接口的代理在创建 `APIClient` 时传入
+    pub proxy: Option<Proxy>,
+    pub transport: Transport,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            reconnect: ReconnectConfig::default().build().into(),
+            liveness_timeout: Duration::from_secs(90),
+            channel_capacity: 100,
+            backpressure: BackpressurePolicy::Block,
+            endpoint: None,
+            protover: 2,
+            recorder: None,
+            proxy: None,
+            transport: Transport::Websocket,
+        }
+    }
+}
+
+pub struct MsgStream {
+    pub rx: MsgReceiver<RoomMessage>,
+    pub rooms: RoomManager,
+}
+
+/// 运行中增减监听的直播间,可以 clone 后交给其他任务使用
+#[derive(Clone)]
+pub struct RoomManager {
+    api_client: APIClient,
+    /// 关闭后所有连接结束时置空,`MsgStream.rx` 随之结束
+    wx: Arc<Mutex<Option<MsgSender<RoomMessage>>>>,
+    options: ConnectOptions,
+    cancel: CancellationToken,
+    rooms: Arc<Mutex<HashMap<RoomId, RoomTask>>>,
+    next_task: Arc<AtomicU64>,
+}
+
+/// 取消后重新订阅的直播间会换一个 `id`,旧任务结束时据此判断条目是否还属于自己
+struct RoomTask {
+    id: u64,
+    handler: JoinHandle<()>,
+}
+
+impl RoomManager {
+    /// 已经在监听或正在关闭时返回 false
+    pub fn subscribe(&self, room_id: RoomId) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(&room_id) || self.cancel.is_cancelled() {
+            return false;
+        }
+        info!("room {} subscribe", room_id);
+        let id = self.next_task.fetch_add(1, Ordering::Relaxed);
+        let handler = tokio::spawn(supervise(self.clone(), room_id, id));
+        rooms.insert(room_id, RoomTask { id, handler });
+        true
+    }
+
+    /// 没有在监听时返回 false
+    pub fn unsubscribe(&self, room_id: RoomId) -> bool {
+        // 先释放锁,`close_if_idle` 还要再锁一次
+        let task = self.rooms.lock().unwrap().remove(&room_id);
+        match task {
+            Some(task) => {
+                info!("room {} unsubscribe", room_id);
+                task.handler.abort();
+                self.close_if_idle();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn rooms(&self) -> Vec<RoomId> {
+        self.rooms.lock().unwrap().keys().copied().collect()
+    }
+
+    /// 关闭所有连接,`MsgStream.rx` 在剩余消息取完后结束
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
+    }
+
+    /// 连接任务结束时调用,条目已经被取消或换成新任务时不做改动
+    fn finish(&self, room_id: RoomId, id: u64) {
+        {
+            let mut rooms = self.rooms.lock().unwrap();
+            match rooms.get(&room_id) {
+                Some(task) if task.id == id => {
+                    rooms.remove(&room_id);
+                }
+                _ => return,
+            }
+        }
+        self.close_if_idle();
+    }
+
+    fn sender(&self) -> Option<MsgSender<RoomMessage>> {
+        self.wx.lock().unwrap().clone()
+    }
+
+    /// 正在关闭且所有连接都已结束时,丢弃发送端
+    fn close_if_idle(&self) {
+        if !self.cancel.is_cancelled() || !self.rooms.lock().unwrap().is_empty() {
+            return;
+        }
+        let recorder = self.options.recorder.clone();
+        let runtime = tokio::runtime::Handle::try_current();
+        match (recorder, runtime) {
+            // 存档写完后再结束消息流
+            (Some(recorder), Ok(runtime)) => {
+                let wx = self.wx.clone();
+                runtime.spawn(async move {
+                    if let Err(e) = recorder.flush().await {
+                        error!("{}", e);
+                    }
+                    wx.lock().unwrap().take();
+                });
+            }
+            _ => {
+                self.wx.lock().unwrap().take();
+            }
+        }
+    }
+}
+
+type WsStream = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type RsStream = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+const BILI_CHAT_SERVER_URL: &'static str = "wss://broadcastlv.chat.bilibili.com/sub";
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 每个直播间一个连接,消息合并到同一个 `MsgStream`。
+/// `cancel` 取消后各连接发送 close 帧,转发完缓冲中的消息后结束
+pub async fn connect(
+    api_client: APIClient,
+    room_ids: &[RoomId],
+    options: ConnectOptions,
+    cancel: CancellationToken,
+) -> MsgStream {
+    let (wx, rx) = channel::channel(options.channel_capacity, options.backpressure);
+    let rooms = RoomManager {
+        api_client,
+        wx: Arc::new(Mutex::new(Some(wx))),
+        options,
+        cancel,
+        rooms: Default::default(),
+        next_task: Default::default(),
+    };
+    for &room_id in room_ids {
+        rooms.subscribe(room_id);
+    }
+    let manager = rooms.clone();
+    tokio::spawn(async move {
+        manager.cancel.cancelled().await;
+        manager.close_if_idle();
+    });
+    MsgStream { rx, rooms }
+}
+
+/// 重连策略放弃后从 `RoomManager` 中移除,可以再次 `subscribe`
+async fn supervise(manager: RoomManager, room_id: RoomId, id: u64) {
+    let wx = match manager.sender() {
+        Some(wx) => wx,
+        None => return,
+    };
+    let r = open_client(
+        manager.api_client.clone(),
+        room_id,
+        wx,
+        manager.options.clone(),
+        manager.cancel.clone(),
+    )
+    .await;
+    match r {
+        Ok(()) => info!("room {} client stop", room_id),
+        Err(e) => error!("room {} client stop {:?}", room_id, e),
+    }
+    manager.finish(room_id, id);
+}
+
+/// `host_list` 为空时使用默认地址
+fn host_urls(host_list: &[LiveHost]) -> Vec<Url> {
+    let urls: Vec<Url> = host_list
+        .iter()
+        .filter(|h| !h.host.is_empty())
+        .filter_map(|h| {
+            let port = if h.wss_port > 0 { h.wss_port } else { 443 };
+            format!("wss://{}:{}/sub", h.host, port).parse().ok()
+        })
+        .collect();
+    if urls.is_empty() {
+        vec![BILI_CHAT_SERVER_URL.parse().unwrap()]
+    } else {
+        urls
+    }
+}
+
+pub async fn open_client(
+    api_client: APIClient,
+    room_id: RoomId,
+    wx: MsgSender<RoomMessage>,
+    options: ConnectOptions,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    let reconnect = options.reconnect.clone();
+    let uid = api_client.uid()?;
+    let mut attempt = 0u32;
+    // 每次重连换下一个服务器
+    let mut host_index = 0usize;
+    loop {
+        if wx.is_closed() || cancel.is_cancelled() {
+            return Ok(());
+        }
+        let start_time = Instant::now();
+        send_state(&wx, room_id, ConnectionState::Connecting).await;
+        let r = connect_once(
+            &api_client,
+            room_id,
+            uid,
+            host_index,
+            &wx,
+            &options,
+            &cancel,
+        )
+        .await;
+        if cancel.is_cancelled() {
+            let reason = "shutdown".to_string();
+            send_state(&wx, room_id, ConnectionState::Disconnected { reason }).await;
+            return Ok(());
+        }
+        let (reason_kind, reason) = match r {
+            Ok(()) => ("closed", "connection closed".to_string()),
+            Err(e) => (e.kind(), e.to_string()),
+        };
+        host_index += 1;
+        send_state(
+            &wx,
+            room_id,
+            ConnectionState::Disconnected {
+                reason: reason.clone(),
+            },
+        )
+        .await;
+
+        let elapsed = start_time.elapsed();
+        if elapsed >= reconnect.reset_after() && attempt > 0 {
+            info!(
+                "room {} connection lasted {:?}, reset reconnect attempt",
+                room_id, elapsed
+            );
+            attempt = 0;
+        }
+        attempt += 1;
+        match reconnect.next_delay(attempt) {
+            Some(delay) => {
+                info!(
+                    "room {} reconnect[{}] after {:?}, reason: {}",
+                    room_id, attempt, delay, reason
+                );
+                let room = room_id.to_string();
+                let labels = [("room", room.as_str()), ("reason", reason_kind)];
+                metrics::inc(&metrics::RECONNECTS, &labels);
+                send_state(
+                    &wx,
+                    room_id,
+                    ConnectionState::Reconnecting { attempt, delay },
+                )
+                .await;
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = cancel.cancelled() => return Ok(()),
+                }
+                info!("room {} reconnect start", room_id);
+            }
+            None => {
+                error!(
+                    "room {} give up after {} attempts, reason: {}",
+                    room_id, attempt, reason
+                );
+                send_state(&wx, room_id, ConnectionState::GaveUp).await;
+                return Err(anyhow!("reconnect fail: {}", reason));
+            }
+        }
+    }
+}
+
+/// 连接断开的原因
+#[derive(ThisError, Debug)]
+enum DisconnectError {
+    #[error("get danmu info {0}")]
+    Api(String),
+    #[error("{0}")]
+    Connect(Error),
+    #[error("auth failed code={0}")]
+    AuthFailed(i64),
+    #[error("no message in {0:?}")]
+    LivenessTimeout(Duration),
+    #[error("receiver closed")]
+    ReceiverClosed,
+    #[error("{0}")]
+    Io(Error),
+}
+
+impl DisconnectError {
+    /// 断线原因,按它分别统计重连次数
+    fn kind(&self) -> &'static str {
+        match self {
+            DisconnectError::Api(_) => "api_error",
+            DisconnectError::Connect(_) => "connect_error",
+            DisconnectError::AuthFailed(_) => "auth_failed",
+            DisconnectError::LivenessTimeout(_) => "liveness_timeout",
+            DisconnectError::ReceiverClosed => "receiver_closed",
+            DisconnectError::Io(_) => "io_error",
+        }
+    }
+}
+
+async fn send_state(wx: &MsgSender<RoomMessage>, room_id: RoomId, state: ConnectionState) {
+    let connected = match state {
+        ConnectionState::Connected { .. } => Some(1.0),
+        ConnectionState::Disconnected { .. } | ConnectionState::GaveUp => Some(0.0),
+        _ => None,
+    };
+    if let Some(connected) = connected {
+        let room = room_id.to_string();
+        metrics::set(&metrics::CONNECTED, &[("room", room.as_str())], connected);
+    }
+    let msg = ServerLiveMessage::Connection(state);
+    if wx.send(RoomMessage { room_id, msg }).await.is_err() {
+        debug!("room {} receiver closed", room_id);
+    }
+}
+
+/// 建立一次连接并一直处理消息,返回时连接已断开
+async fn connect_once(
+    api_client: &APIClient,
+    room_id: RoomId,
+    uid: Uid,
+    host_index: usize,
+    wx: &MsgSender<RoomMessage>,
+    options: &ConnectOptions,
+    cancel: &CancellationToken,
+) -> Result<(), DisconnectError> {
+    let info = crate::bili_api::get_danmu_info(api_client, room_id)
+        .await
+        .map_err(|e| DisconnectError::Api(e.to_string()))?;
+    let info = if let APIResult {
+        code: 0,
+        data: Some(info),
+        ..
+    } = info
+    {
+        info
+    } else {
+        return Err(DisconnectError::Api(format!("{:?}", info)));
+    };
+
+    let ws_login = WsLogin {
+        room_id,
+        uid,
+        key: info.token.clone(),
+        protover: options.protover,
+    };
+    if options.transport == Transport::Tcp {
+        let hosts = match options.endpoint.as_ref() {
+            Some(endpoint) => vec![(
+                endpoint.host_str().unwrap_or_default().to_string(),
+                endpoint.port().unwrap_or(2243),
+            )],
+            None => tcp_hosts(&info.host_list),
+        };
+        let (host, port) = &hosts[host_index % hosts.len()];
+        return connect_tcp(host, *port, room_id, ws_login, wx, options, cancel).await;
+    }
+
+    let urls = match options.endpoint.as_ref() {
+        Some(endpoint) => vec![endpoint.clone()],
+        None => host_urls(&info.host_list),
+    };
+    let url = &urls[host_index % urls.len()];
+
+    info!("room {} connect {}", room_id, url);
+    let (ws_stream, _) = tokio::select! {
+        r = ws_connect(url, options.proxy.as_ref()) => r.map_err(DisconnectError::Connect)?,
+        _ = cancel.cancelled() => return Ok(()),
+    };
+    let host = url.host_str().unwrap_or_default().to_string();
+    send_state(wx, room_id, ConnectionState::Connected { host }).await;
+    let (mut w_stream, mut r_stream) = ws_stream.split();
+    let heartbeat = Heartbeat::default();
+    let recorder = options.recorder.as_ref();
+    // 任意一边结束就断开整个连接
+    let r = tokio::select! {
+        r = connect_keep(&mut w_stream, ws_login, &heartbeat) => r,
+        r = loop_handle_msg(&mut r_stream, room_id, wx.clone(), options.liveness_timeout, recorder, &heartbeat) => r,
+        _ = cancel.cancelled() => Ok(()),
+    };
+    if cancel.is_cancelled() {
+        close_gracefully(&mut w_stream, &mut r_stream, room_id, wx, options).await;
+        return Ok(());
+    }
+    info!("room {} client close {:?}", room_id, r);
+    r
+}
+
+async fn ws_connect(
+    url: &Url,
+    proxy: Option<&Proxy>,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error> {
+    let r = match proxy {
+        None => connect_async(url).await,
+        Some(proxy) => {
+            let host = url.host_str().unwrap_or_default();
+            let port = url.port_or_known_default().unwrap_or(443);
+            let stream = proxy.connect(host, port).await?;
+            client_async_tls(url, stream).await
+        }
+    };
+    r.map_err(|e| anyhow!("ws connect {} {:?}", url, e))
+}
+
+/// 发送 close 帧,等待服务器关闭连接,期间收到的消息照常转发
+async fn close_gracefully(
+    w_stream: &mut WsStream,
+    r_stream: &mut RsStream,
+    room_id: RoomId,
+    wx: &MsgSender<RoomMessage>,
+    options: &ConnectOptions,
+) {
+    info!("room {} shutdown, close connection", room_id);
+    if let Err(e) = w_stream.send(Message::Close(None)).await {
+        warn!("room {} send close {:?}", room_id, e);
+        return;
+    }
+    let recorder = options.recorder.as_ref();
+    let heartbeat = Heartbeat::default();
+    let drain = loop_handle_msg(
+        r_stream,
+        room_id,
+        wx.clone(),
+        CLOSE_TIMEOUT,
+        recorder,
+        &heartbeat,
+    );
+    match tokio::time::timeout(CLOSE_TIMEOUT, drain).await {
+        Ok(r) => info!("room {} closed {:?}", room_id, r),
+        Err(_) => warn!("room {} close timeout", room_id),
+    }
+}
+
+/// 记录最近一次心跳的发送时间,收到回应时计算往返时间
+#[derive(Default)]
+struct Heartbeat {
+    sent: Mutex<Option<Instant>>,
+}
+
+impl Heartbeat {
+    fn sent(&self) {
+        *self.sent.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn received(&self, room_id: RoomId) {
+        if let Some(sent) = self.sent.lock().unwrap().take() {
+            let room = room_id.to_string();
+            let rtt = sent.elapsed().as_secs_f64();
+            metrics::set(&metrics::HEARTBEAT_RTT, &[("room", room.as_str())], rtt);
+        }
+    }
+}
+
+async fn connect_keep(
+    client: &mut WsStream,
+    ws_login: WsLogin,
+    heartbeat: &Heartbeat,
+) -> Result<(), DisconnectError> {
+    client
+        .send(Message::Binary(ClientLiveMessage::Login(ws_login).encode()))
+        .await
+        .map_err(|e| DisconnectError::Io(anyhow!("{:?}", e)))?;
+    loop {
+        debug!("heartbeat");
+        client
+            .send(Message::Binary(ClientLiveMessage::ClientHeartBeat.encode()))
+            .await
+            .map_err(|e| DisconnectError::Io(anyhow!("{:?}", e)))?;
+        heartbeat.sent();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn loop_handle_msg(
+    client: &mut RsStream,
+    room_id: RoomId,
+    wx: MsgSender<RoomMessage>,
+    liveness_timeout: Duration,
+    recorder: Option<&ArchiveWriter>,
+    heartbeat: &Heartbeat,
+) -> Result<(), DisconnectError> {
+    let mut msg_list = LinkedList::new();
+    loop {
+        let msg = match tokio::time::timeout(liveness_timeout, client.next()).await {
+            Ok(Some(msg)) => msg.map_err(|e| DisconnectError::Io(e.into()))?,
+            Ok(None) => break,
+            Err(_) => return Err(DisconnectError::LivenessTimeout(liveness_timeout)),
+        };
+        match msg {
+            Message::Text(text) => {
+                debug!("recv text {}", text)
+            }
+            Message::Binary(bin) => {
+                forward_package(bin, room_id, &wx, recorder, heartbeat, &mut msg_list).await?;
+            }
+            Message::Ping(_) => debug!("ws ping"),
+            Message::Pong(_) => debug!("ws pong"),
+            Message::Close(_) => warn!("ws close"),
+        }
+    }
+    warn!("ws handle loop stop");
+    Ok(())
+}
+
+/// 解码一个或多个包并转发,认证失败时返回错误
+async fn forward_package(
+    bin: Vec<u8>,
+    room_id: RoomId,
+    wx: &MsgSender<RoomMessage>,
+    recorder: Option<&ArchiveWriter>,
+    heartbeat: &Heartbeat,
+    msg_list: &mut LinkedList<ServerLiveMessage>,
+) -> Result<(), DisconnectError> {
+    if let Err(e) = decode_from_server(bin, msg_list) {
+        metrics::inc(&metrics::DECODE_ERRORS, &[("kind", e.kind())]);
+        match e {
+            MsgDecodeError::LoginFail(code) => {
+                return Err(DisconnectError::AuthFailed(code));
+            }
+            e => error!("handler msg {:?}", e),
+        }
+    }
+    while let Some(msg) = msg_list.pop_front() {
+        match msg {
+            ServerLiveMessage::LoginAck => {
+                debug!("LoginAck");
+            }
+            ServerLiveMessage::Notification(ref notification) => {
+                debug!("Notification");
+                if let Some(recorder) = recorder {
+                    if let Err(e) = recorder.record(room_id, notification).await {
+                        error!("{}", e);
+                    }
+                }
+            }
+            ServerLiveMessage::ServerHeartBeat => {
+                debug!("ServerHeartBeat");
+                heartbeat.received(room_id);
+            }
+            ServerLiveMessage::Connection(_) => {}
+        }
+        wx.send(RoomMessage { room_id, msg })
+            .await
+            .map_err(|_| DisconnectError::ReceiverClosed)?;
+    }
+    Ok(())
+}
+
+/// tcp 连接,包格式与 websocket 的二进制消息相同,按包头中的长度切分
+async fn connect_tcp(
+    host: &str,
+    port: u16,
+    room_id: RoomId,
+    ws_login: WsLogin,
+    wx: &MsgSender<RoomMessage>,
+    options: &ConnectOptions,
+    cancel: &CancellationToken,
+) -> Result<(), DisconnectError> {
+    info!("room {} connect tcp {}:{}", room_id, host, port);
+    let stream = tokio::select! {
+        r = tcp_connect(host, port, options.proxy.as_ref()) => r.map_err(DisconnectError::Connect)?,
+        _ = cancel.cancelled() => return Ok(()),
+    };
+    let _ = stream.set_nodelay(true);
+    let host = host.to_string();
+    send_state(wx, room_id, ConnectionState::Connected { host }).await;
+    let (mut r_stream, mut w_stream) = stream.into_split();
+    let recorder = options.recorder.as_ref();
+    let heartbeat = Heartbeat::default();
+    let r = tokio::select! {
+        r = tcp_keep(&mut w_stream, ws_login, &heartbeat) => r,
+        r = loop_handle_tcp(&mut r_stream, room_id, wx, options.liveness_timeout, recorder, &heartbeat) => r,
+        _ = cancel.cancelled() => Ok(()),
+    };
+    if cancel.is_cancelled() {
+        info!("room {} shutdown, close connection", room_id);
+        let _ = w_stream.shutdown().await;
+        let drain = loop_handle_tcp(
+            &mut r_stream,
+            room_id,
+            wx,
+            CLOSE_TIMEOUT,
+            recorder,
+            &heartbeat,
+        );
+        match tokio::time::timeout(CLOSE_TIMEOUT, drain).await {
+            Ok(r) => info!("room {} closed {:?}", room_id, r),
+            Err(_) => warn!("room {} close timeout", room_id),
+        }
+        return Ok(());
+    }
+    info!("room {} client close {:?}", room_id, r);
+    r
+}
+
+async fn tcp_connect(host: &str, port: u16, proxy: Option<&Proxy>) -> Result<TcpStream, Error> {
+    match proxy {
+        None => TcpStream::connect((host, port))
+            .await
+            .map_err(|e| anyhow!("tcp connect {}:{} {}", host, port, e)),
+        Some(proxy) => proxy.connect(host, port).await,
+    }
+}
+
+async fn tcp_keep(
+    client: &mut OwnedWriteHalf,
+    ws_login: WsLogin,
+    heartbeat: &Heartbeat,
+) -> Result<(), DisconnectError> {
+    client
+        .write_all(&ClientLiveMessage::Login(ws_login).encode())
+        .await
+        .map_err(|e| DisconnectError::Io(e.into()))?;
+    loop {
+        debug!("heartbeat");
+        client
+            .write_all(&ClientLiveMessage::ClientHeartBeat.encode())
+            .await
+            .map_err(|e| DisconnectError::Io(e.into()))?;
+        heartbeat.sent();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn loop_handle_tcp(
+    client: &mut OwnedReadHalf,
+    room_id: RoomId,
+    wx: &MsgSender<RoomMessage>,
+    liveness_timeout: Duration,
+    recorder: Option<&ArchiveWriter>,
+    heartbeat: &Heartbeat,
+) -> Result<(), DisconnectError> {
+    let mut msg_list = LinkedList::new();
+    loop {
+        let package = match tokio::time::timeout(liveness_timeout, read_package(client)).await {
+            Ok(Ok(Some(package))) => package,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(DisconnectError::Io(e)),
+            Err(_) => return Err(DisconnectError::LivenessTimeout(liveness_timeout)),
+        };
+        forward_package(package, room_id, wx, recorder, heartbeat, &mut msg_list).await?;
+    }
+    warn!("tcp handle loop stop");
+    Ok(())
+}
+
+#[test]
+fn host_urls_test() {
+    let host = |host: &str, wss_port: u32| LiveHost {
+        host: host.to_string(),
+        port: 2243,
+        ws_port: 2244,
+        wss_port,
+    };
+    let urls = host_urls(&[
+        host("a.chat.bilibili.com", 443),
+        host("", 443),
+        host("b", 0),
+    ]);
+    let urls: Vec<&str> = urls.iter().map(|u| u.as_str()).collect();
+    assert_eq!(urls, vec!["wss://a.chat.bilibili.com/sub", "wss://b/sub"]);
+    assert_eq!(host_urls(&[])[0].as_str(), BILI_CHAT_SERVER_URL);
+}
+
+#[test]
+fn unsubscribe_after_shutdown_test() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (wx, mut rx) = channel::channel(10, BackpressurePolicy::Block);
+    let manager = RoomManager {
+        api_client: APIClient::guest(None).unwrap(),
+        wx: Arc::new(Mutex::new(Some(wx))),
+        options: ConnectOptions::default(),
+        cancel: CancellationToken::new(),
+        rooms: Default::default(),
+        next_task: Default::default(),
+    };
+    let handler = rt.spawn(futures_util::future::pending());
+    let task = RoomTask { id: 0, handler };
+    manager.rooms.lock().unwrap().insert(RoomId(1), task);
+    manager.shutdown();
+    assert!(!manager.subscribe(RoomId(2)));
+
+    // 死锁时阻塞的是线程,放到单独的线程上才能超时
+    let (done_wx, done_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || done_wx.send(manager.unsubscribe(RoomId(1))));
+    let r = done_rx.recv_timeout(std::time::Duration::from_secs(1));
+    if r != Ok(true) {
+        // 卡住的线程还持有任务句柄,不等待 runtime 关闭
+        std::mem::forget(rt);
+        panic!("unsubscribe deadlock {:?}", r);
+    }
+    assert!(rt.block_on(rx.recv()).is_none());
+}
+
+#[tokio::test]
+async fn subscribe_test() {
+    let (wx, _rx) = channel::channel(10, BackpressurePolicy::Block);
+    let manager = RoomManager {
+        api_client: APIClient::guest(None).unwrap(),
+        wx: Arc::new(Mutex::new(Some(wx))),
+        options: ConnectOptions::default(),
+        cancel: CancellationToken::new(),
+        rooms: Default::default(),
+        next_task: Default::default(),
+    };
+    assert!(manager.subscribe(RoomId(1)));
+    assert!(!manager.subscribe(RoomId(1)));
+    assert!(manager.subscribe(RoomId(2)));
+    let mut rooms = manager.rooms();
+    rooms.sort();
+    assert_eq!(rooms, vec![RoomId(1), RoomId(2)]);
+
+    assert!(manager.unsubscribe(RoomId(1)));
+    assert!(!manager.unsubscribe(RoomId(1)));
+    assert_eq!(manager.rooms(), vec![RoomId(2)]);
+    // 取消后可以重新订阅
+    assert!(manager.subscribe(RoomId(1)));
+    assert!(manager.unsubscribe(RoomId(1)));
+    assert!(manager.unsubscribe(RoomId(2)));
+    assert!(manager.rooms().is_empty());
+    // 没有关闭时取消所有订阅不会结束消息流
+    assert!(manager.sender().is_some());
+
+    // 取消订阅的旧任务结束时不会移除重新订阅的新任务
+    let handler = tokio::spawn(futures_util::future::pending());
+    let task = RoomTask { id: 5, handler };
+    manager.rooms.lock().unwrap().insert(RoomId(3), task);
+    manager.finish(RoomId(3), 4);
+    assert_eq!(manager.rooms(), vec![RoomId(3)]);
+    manager.finish(RoomId(3), 5);
+    assert!(manager.rooms().is_empty());
+}
+
+#[tokio::test]
+async fn client_test() {
+    env_logger::init();
+    let client = crate::bili_api::get_client(None).await.unwrap();
+    let options = ConnectOptions::default();
+    let mut s = connect(client, &[RoomId(421296)], options, CancellationToken::new()).await;
+    while let Some(x) = s.rx.recv().await {
+        info!("{:?}", x);
+    }
+}
+
+#[test]
+fn qr_test() {
+    use qrcode::render::unicode;
+    use qrcode::QrCode;
+
+    let code = QrCode::new(
+        "https://passport.bilibili.com/qrcode/h5/login?oauthKey=beb978f21de4a6dbcba53c720e155560",
+    )
+    .unwrap();
+    let image = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        // .quiet_zone(true)
+        .build();
+    println!("qrcode");
+    println!("{}", image);
+}
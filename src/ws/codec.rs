@@ -0,0 +1,312 @@
+use crate::metrics;
+use crate::ws::catalogue;
+use crate::ws::message::notification_msg::NotificationMsg;
+use crate::ws::message::{ClientLiveMessage, ServerLiveMessage, WsLogin};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::LinkedList;
+use std::io::Cursor;
+use std::io::Read;
+use thiserror::Error;
+
+impl ClientLiveMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ClientLiveMessage::Login(WsLogin {
+                room_id,
+                uid,
+                key,
+                protover,
+            }) => {
+                let uid = if uid.0 > 0 { Some(uid.0) } else { None };
+                let payload = serde_json::json!({
+                        "uid": uid,
+                        "roomid": room_id.0,
+                        "protover": *protover,
+                        "platform": "web",
+                        "type": 2,
+                        "key": key})
+                .to_string();
+                let payload_len = payload.len();
+                let package_len = 16 + payload_len;
+
+                let mut package = Vec::<u8>::with_capacity(package_len);
+                package
+                    .write_u32::<NetworkEndian>(package_len as u32)
+                    .unwrap();
+                package.write_u16::<NetworkEndian>(16).unwrap();
+                package.write_u16::<NetworkEndian>(1).unwrap();
+                package.write_u32::<NetworkEndian>(7).unwrap();
+                package.write_u32::<NetworkEndian>(1).unwrap();
+                package.extend_from_slice(payload.as_bytes());
+                package
+            }
+            ClientLiveMessage::ClientHeartBeat => {
+                let payload = b"[object Object]";
+                let payload_len = payload.len();
+                let package_len = 16 + payload_len;
+
+                let mut package = Vec::<u8>::with_capacity(package_len);
+                package
+                    .write_u32::<NetworkEndian>(package_len as u32)
+                    .unwrap();
+                package.write_u16::<NetworkEndian>(16).unwrap();
+                package.write_u16::<NetworkEndian>(1).unwrap();
+                package.write_u32::<NetworkEndian>(2).unwrap();
+                package.write_u32::<NetworkEndian>(1).unwrap();
+                package.extend_from_slice(payload);
+                package
+            }
+        }
+    }
+}
+
+/// 只读取通知的 cmd,其他字段跳过
+#[derive(Deserialize)]
+struct CmdPeek<'a> {
+    #[serde(borrow, default)]
+    cmd: Cow<'a, str>,
+}
+
+const HEADER_LEN: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum MsgDecodeError {
+    #[error("bad header")]
+    BadHeader,
+    #[error("package truncated")]
+    Truncated,
+    #[error("useless msg:type = {0}")]
+    UselessMsg(usize),
+    #[error("inflate error {0}")]
+    InflateError(String),
+    #[error("undefine msg v={pkg_v:?} type={pkg_type:?}")]
+    UndefinedMsg { pkg_v: u16, pkg_type: u32 },
+    #[error("decode body is error {0}")]
+    DecodeBodyError(String),
+    #[error("login fail code={0}")]
+    LoginFail(i64),
+}
+
+impl MsgDecodeError {
+    /// 解码失败计数中 `kind` 标签的取值
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MsgDecodeError::BadHeader => "bad_header",
+            MsgDecodeError::Truncated => "truncated",
+            MsgDecodeError::UselessMsg(_) => "useless_msg",
+            MsgDecodeError::InflateError(_) => "inflate_error",
+            MsgDecodeError::UndefinedMsg { .. } => "undefined_msg",
+            MsgDecodeError::DecodeBodyError(_) => "decode_body_error",
+            MsgDecodeError::LoginFail(_) => "login_fail",
+        }
+    }
+}
+
+pub fn decode_from_server(
+    data: Vec<u8>,
+    result_list: &mut LinkedList<ServerLiveMessage>,
+) -> Result<(), MsgDecodeError> {
+    let mut buff_len = data.len();
+    let mut buff = Cursor::new(data);
+    'start: loop {
+        let package_length = buff
+            .read_u32::<NetworkEndian>()
+            .map_err(|_| MsgDecodeError::BadHeader)? as usize;
+        let package_head_length = buff
+            .read_u16::<NetworkEndian>()
+            .map_err(|_| MsgDecodeError::BadHeader)? as usize;
+        let package_version = buff
+            .read_u16::<NetworkEndian>()
+            .map_err(|_| MsgDecodeError::BadHeader)?;
+        let package_type = buff
+            .read_u32::<NetworkEndian>()
+            .map_err(|_| MsgDecodeError::BadHeader)?;
+        let package_other = buff
+            .read_u32::<NetworkEndian>()
+            .map_err(|_| MsgDecodeError::BadHeader)?;
+
+        trace!(
+            "package_version={} package_other={}",
+            package_version,
+            package_other
+        );
+        // tcp 连接直接读取包头中的长度,不能信任
+        if !(HEADER_LEN..=package_length).contains(&package_head_length) {
+            return Err(MsgDecodeError::BadHeader);
+        }
+        let mut extra_head = vec![0; package_head_length - HEADER_LEN];
+        buff.read_exact(&mut extra_head)
+            .map_err(|_| MsgDecodeError::Truncated)?;
+
+        if package_version == 2 {
+            let mut package_body = vec![];
+            let _ = buff.read_to_end(&mut package_body);
+
+            let new_data = inflate::inflate_bytes_zlib(package_body.as_slice())
+                .map_err(|e| MsgDecodeError::InflateError(e))?;
+
+            buff_len = new_data.len();
+            buff = Cursor::new(new_data);
+            // tail call
+            continue 'start;
+        }
+        if package_version > 2 {
+            return Err(MsgDecodeError::UndefinedMsg {
+                pkg_v: package_version,
+                pkg_type: package_type,
+            });
+        }
+
+        let package_body_len = package_length - package_head_length;
+        let mut package_body = vec![0; package_body_len];
+        buff.read_exact(package_body.as_mut_slice())
+            .map_err(|_| MsgDecodeError::Truncated)?;
+
+        match package_type {
+            3 => result_list.push_back(ServerLiveMessage::ServerHeartBeat),
+            5 => {
+                let body = package_body.as_slice();
+                let cmd = serde_json::from_slice::<CmdPeek>(body)
+                    .map_err(|e| MsgDecodeError::DecodeBodyError(e.to_string()))?
+                    .cmd;
+                let notification_msg = serde_json::from_slice::<NotificationMsg>(body)
+                    .map_err(|e| MsgDecodeError::DecodeBodyError(e.to_string()))?;
+                // 未定义的 cmd 合并为一个标签,指标的序列数不随服务器新增的 cmd 增长
+                let label = match notification_msg {
+                    NotificationMsg::Unknown => "unknown",
+                    _ => &cmd,
+                };
+                metrics::inc(&metrics::NOTIFICATIONS, &[("cmd", label)]);
+                match notification_msg {
+                    // 记录后继续解码同一批中后面的包
+                    NotificationMsg::Unknown => {
+                        debug!("unknown cmd {}", cmd);
+                        // 只有未定义的 cmd 才需要完整的 json
+                        if let Ok(value) = serde_json::from_slice(body) {
+                            catalogue::record(&cmd, &value);
+                        }
+                    }
+                    notification_msg => {
+                        result_list.push_back(ServerLiveMessage::Notification(notification_msg))
+                    }
+                }
+            }
+            8 => {
+                // 认证成功时为 {"code":0}
+                let code = serde_json::from_slice::<serde_json::Value>(package_body.as_slice())
+                    .ok()
+                    .and_then(|v| v.get("code").and_then(|c| c.as_i64()))
+                    .unwrap_or(0);
+                if code != 0 {
+                    return Err(MsgDecodeError::LoginFail(code));
+                }
+                result_list.push_back(ServerLiveMessage::LoginAck)
+            }
+            _ => {
+                return Err(MsgDecodeError::UndefinedMsg {
+                    pkg_v: package_version,
+                    pkg_type: package_type,
+                });
+            }
+        };
+        if buff.position() < buff_len as u64 {
+            continue 'start;
+        } else {
+            break 'start;
+        }
+    }
+    Ok(())
+}
+
+/// 测试用的服务器包,不压缩
+#[cfg(test)]
+fn package(op: u32, body: &[u8]) -> Vec<u8> {
+    let mut package = vec![];
+    package
+        .write_u32::<NetworkEndian>(16 + body.len() as u32)
+        .unwrap();
+    package.write_u16::<NetworkEndian>(16).unwrap();
+    package.write_u16::<NetworkEndian>(1).unwrap();
+    package.write_u32::<NetworkEndian>(op).unwrap();
+    package.write_u32::<NetworkEndian>(1).unwrap();
+    package.extend_from_slice(body);
+    package
+}
+
+#[test]
+fn login_ack_test() {
+    let mut list = LinkedList::new();
+    decode_from_server(package(8, br#"{"code":0}"#), &mut list).unwrap();
+    assert!(matches!(list.pop_front(), Some(ServerLiveMessage::LoginAck)));
+    let r = decode_from_server(package(8, br#"{"code":-101}"#), &mut list);
+    assert!(matches!(r, Err(MsgDecodeError::LoginFail(-101))));
+}
+
+#[test]
+fn unknown_cmd_test() {
+    // 同一批中未定义的 cmd 不影响后面的包
+    let mut batch = package(5, br#"{"cmd":"NOT_A_CMD","data":{}}"#);
+    batch.extend(package(5, br#"{"cmd":"LIVE"}"#));
+    let mut list = LinkedList::new();
+    decode_from_server(batch, &mut list).unwrap();
+    assert_eq!(list.len(), 1);
+    assert!(matches!(
+        list.pop_front(),
+        Some(ServerLiveMessage::Notification(
+            NotificationMsg::LIVE { .. }
+        ))
+    ));
+
+    let r = decode_from_server(package(5, br#"{"cmd":"SEND_GIFT"}"#), &mut list);
+    assert!(matches!(r, Err(MsgDecodeError::DecodeBodyError(_))));
+}
+
+#[test]
+fn client_package_test() {
+    // 包头中的长度是整个包的长度,连续发送的包靠它切分
+    fn split(data: &[u8]) -> (u32, &[u8]) {
+        let mut header = Cursor::new(data);
+        let package_len = header.read_u32::<NetworkEndian>().unwrap() as usize;
+        assert_eq!(package_len, data.len());
+        assert_eq!(header.read_u16::<NetworkEndian>().unwrap(), 16);
+        assert_eq!(header.read_u16::<NetworkEndian>().unwrap(), 1);
+        let op = header.read_u32::<NetworkEndian>().unwrap();
+        assert_eq!(header.read_u32::<NetworkEndian>().unwrap(), 1);
+        (op, &data[16..])
+    }
+
+    let login = ClientLiveMessage::Login(WsLogin {
+        room_id: crate::id::RoomId(1),
+        uid: crate::id::Uid(2),
+        key: "key".to_string(),
+        protover: 2,
+    })
+    .encode();
+    let (op, body) = split(&login);
+    assert_eq!(op, 7);
+    let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+    assert_eq!(body["roomid"], 1);
+    assert_eq!(body["key"], "key");
+
+    let heartbeat = ClientLiveMessage::ClientHeartBeat.encode();
+    let (op, body) = split(&heartbeat);
+    assert_eq!(op, 2);
+    assert_eq!(body, b"[object Object]");
+}
+
+#[test]
+fn bad_length_test() {
+    let mut list = LinkedList::new();
+    // 包头长度大于包长度
+    let header = [0, 0, 0, 16, 0, 20, 0, 1, 0, 0, 0, 5, 0, 0, 0, 1];
+    let r = decode_from_server(header.to_vec(), &mut list);
+    assert!(matches!(r, Err(MsgDecodeError::BadHeader)));
+    // 包长度超过实际数据
+    let mut package = vec![0, 0, 0, 32, 0, 16, 0, 1, 0, 0, 0, 5, 0, 0, 0, 1];
+    package.extend_from_slice(b"{}");
+    let r = decode_from_server(package, &mut list);
+    assert!(matches!(r, Err(MsgDecodeError::Truncated)));
+    assert!(list.is_empty());
+}
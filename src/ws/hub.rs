@@ -0,0 +1,192 @@
+use crate::metrics;
+use crate::ws::channel::{channel, BackpressurePolicy, MsgReceiver, MsgSender, TrySendError};
+use crate::ws::RoomMessage;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+pub type Filter = Box<dyn Fn(&RoomMessage) -> bool + Send + Sync>;
+
+struct Subscriber {
+    name: String,
+    filter: Filter,
+    wx: MsgSender<Arc<RoomMessage>>,
+}
+
+/// 把一个消息流分发给多个订阅者,每个订阅者有自己的过滤条件和队列。
+/// `Block` 订阅者由单独的任务转发,队列满时消息暂存在它自己的缓冲中,
+/// 不影响其他订阅者和消息源;其他策略按各自的方式丢弃。订阅者关闭后自动移除
+#[derive(Clone, Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn subscribe<F>(
+        &self,
+        name: &str,
+        capacity: usize,
+        policy: BackpressurePolicy,
+        filter: F,
+    ) -> MsgReceiver<Arc<RoomMessage>>
+    where
+        F: Fn(&RoomMessage) -> bool + Send + Sync + 'static,
+    {
+        let (wx, rx) = channel(capacity, policy);
+        let wx = match policy {
+            BackpressurePolicy::Block => forward(name, wx),
+            _ => wx,
+        };
+        self.subscribers.lock().unwrap().push(Subscriber {
+            name: name.to_string(),
+            filter: Box::new(filter),
+            wx,
+        });
+        info!("hub subscribe {}", name);
+        rx
+    }
+
+    /// 不等待任何订阅者
+    pub fn publish(&self, msg: RoomMessage) {
+        let msg = Arc::new(msg);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|sub| {
+            if !(sub.filter)(&msg) {
+                return true;
+            }
+            if let Err(TrySendError::Closed(_)) = sub.wx.try_send(msg.clone()) {
+                warn!("hub subscriber {} closed, remove", sub.name);
+                return false;
+            }
+            let depth = sub.wx.len() as f64;
+            metrics::set(&metrics::CHANNEL_DEPTH, &[("channel", &sub.name)], depth);
+            true
+        });
+    }
+
+    /// 转发 `rx` 中的所有消息,`rx` 结束后关闭所有订阅者
+    pub fn run(self, mut rx: MsgReceiver<RoomMessage>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let depth = rx.len() as f64;
+                metrics::set(&metrics::CHANNEL_DEPTH, &[("channel", "source")], depth);
+                self.publish(msg);
+            }
+            if rx.dropped() > 0 {
+                warn!("hub source dropped {} messages", rx.dropped());
+            }
+            info!("hub source closed");
+            self.subscribers.lock().unwrap().clear();
+        })
+    }
+}
+
+/// 返回不限长度的缓冲,由单独的任务等待 `wx` 有空位后转发。
+/// `wx` 的接收端关闭后任务结束,缓冲随之关闭
+fn forward(name: &str, wx: MsgSender<Arc<RoomMessage>>) -> MsgSender<Arc<RoomMessage>> {
+    let (buf_wx, mut buf_rx) = channel(1, BackpressurePolicy::Unbounded);
+    let name = name.to_string();
+    tokio::spawn(async move {
+        while let Some(msg) = buf_rx.recv().await {
+            if wx.send(msg).await.is_err() {
+                break;
+            }
+        }
+        debug!("hub subscriber {} forward stop", name);
+    });
+    buf_wx
+}
+
+#[tokio::test]
+async fn hub_test() {
+    use crate::id::RoomId;
+    use crate::ws::ServerLiveMessage;
+    use std::time::Duration;
+
+    let hub = Hub::new();
+    let mut all = hub.subscribe("all", 10, BackpressurePolicy::Block, |_| true);
+    let mut room_2 = hub.subscribe("room_2", 10, BackpressurePolicy::Block, |m| {
+        m.room_id == RoomId(2)
+    });
+    let slow = hub.subscribe("slow", 1, BackpressurePolicy::DropOldest, |_| true);
+    let crashed = hub.subscribe("crashed", 1, BackpressurePolicy::DropOldest, |_| true);
+    drop(crashed);
+
+    for room_id in [RoomId(1), RoomId(2), RoomId(1)] {
+        let msg = ServerLiveMessage::ServerHeartBeat;
+        hub.publish(RoomMessage { room_id, msg });
+    }
+    // Block 订阅者的消息由转发任务写入
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(hub.subscribers.lock().unwrap().len(), 3);
+    assert_eq!(all.len(), 3);
+    assert_eq!(room_2.recv().await.unwrap().room_id, RoomId(2));
+    assert_eq!(slow.len(), 1);
+    assert_eq!(slow.dropped(), 2);
+    all.try_recv().unwrap();
+}
+
+#[tokio::test]
+async fn hub_block_test() {
+    use crate::id::RoomId;
+    use crate::ws::ServerLiveMessage;
+
+    let hub = Hub::new();
+    let mut blocked = hub.subscribe("blocked", 1, BackpressurePolicy::Block, |_| true);
+    let (source_wx, source_rx) = channel(10, BackpressurePolicy::Block);
+    let handle = hub.clone().run(source_rx);
+    for i in 0..3 {
+        let msg = ServerLiveMessage::ServerHeartBeat;
+        source_wx
+            .send(RoomMessage {
+                room_id: RoomId(i),
+                msg,
+            })
+            .await
+            .unwrap();
+    }
+    drop(source_wx);
+
+    // 队列满时等待,不丢消息
+    let mut rooms = vec![];
+    while let Some(msg) = blocked.recv().await {
+        rooms.push(msg.room_id);
+    }
+    assert_eq!(rooms, vec![RoomId(0), RoomId(1), RoomId(2)]);
+    assert_eq!(blocked.dropped(), 0);
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn hub_block_isolation_test() {
+    use crate::id::RoomId;
+    use crate::ws::ServerLiveMessage;
+    use std::time::Duration;
+
+    let hub = Hub::new();
+    // 不取消息的 Block 订阅者
+    let full = hub.subscribe("full", 1, BackpressurePolicy::Block, |_| true);
+    let mut other = hub.subscribe("other", 10, BackpressurePolicy::Block, |_| true);
+    let (source_wx, source_rx) = channel(1, BackpressurePolicy::Block);
+    let handle = hub.clone().run(source_rx);
+    for i in 0..5 {
+        let msg = ServerLiveMessage::ServerHeartBeat;
+        let msg = RoomMessage {
+            room_id: RoomId(i),
+            msg,
+        };
+        let send = tokio::time::timeout(Duration::from_secs(1), source_wx.send(msg));
+        assert!(send.await.unwrap().is_ok());
+    }
+    for i in 0..5 {
+        let msg = tokio::time::timeout(Duration::from_secs(1), other.recv());
+        assert_eq!(msg.await.unwrap().unwrap().room_id, RoomId(i));
+    }
+    assert_eq!(full.len(), 1);
+    drop(source_wx);
+    drop(full);
+    handle.await.unwrap();
+}
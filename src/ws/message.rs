@@ -1,11 +1,9 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use std::collections::LinkedList;
-use std::io::Cursor;
-use std::io::Read;
-use thiserror::Error;
+use crate::id::{RoomId, Uid};
+use std::time::Duration;
 
 #[allow(non_camel_case_types)]
 pub mod notification_msg {
+    use crate::id::{RoomId, Uid};
     use serde::de::Error;
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
@@ -13,7 +11,11 @@ pub mod notification_msg {
     #[derive(Deserialize, Serialize, Debug)]
     #[serde(tag = "cmd")]
     pub enum NotificationMsg {
-        LIVE {},
+        LIVE {
+            #[serde(default)]
+            live_time: u64,
+        },
+        PREPARING {},
         #[serde(rename = "DANMU_MSG:4:0:2:2:2:0")]
         DANMU_MSG_N {
             info: DanmuMsg,
@@ -66,19 +68,60 @@ pub mod notification_msg {
         AREA_RANK_CHANGED {},
         LIKE_INFO_V3_UPDATE {},
         LIKE_INFO_V3_CLICK {},
+        /// 未定义的 cmd,解码时记录到 cmd 目录后丢弃
+        #[serde(other)]
+        Unknown,
     }
 
     #[derive(Serialize, Debug)]
     pub struct DanmuMsg {
-        pub uid: u64,
+        pub uid: Uid,
         pub uname: String,
 
         pub medal_lv: u32,
         pub medal_name: String,
-        pub medal_owner_uid: u64,
+        pub medal_owner_uid: Uid,
         pub medal_owner_name: String,
+        /// 0 不是大航海 1 总督 2 提督 3 舰长
+        pub guard_level: u32,
 
         pub text: String,
+
+        /// 1 滚动 4 底部 5 顶部
+        pub mode: u32,
+        pub font_size: u32,
+        pub color: u32,
+        /// 发送时间 毫秒
+        pub timestamp: u64,
+    }
+
+    /// `DanmuMsg` 自身序列化后的格式,用于读取存档
+    #[derive(Deserialize)]
+    struct DanmuMsgFields {
+        #[serde(default)]
+        uid: Uid,
+        #[serde(default)]
+        uname: String,
+        #[serde(default)]
+        medal_lv: u32,
+        #[serde(default)]
+        medal_name: String,
+        #[serde(default)]
+        medal_owner_uid: Uid,
+        #[serde(default)]
+        medal_owner_name: String,
+        #[serde(default)]
+        guard_level: u32,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        mode: u32,
+        #[serde(default)]
+        font_size: u32,
+        #[serde(default)]
+        color: u32,
+        #[serde(default)]
+        timestamp: u64,
     }
 
     impl<'de> Deserialize<'de> for DanmuMsg {
@@ -89,8 +132,11 @@ pub mod notification_msg {
             let info = serde_json::Value::deserialize(deserializer)?;
             match info {
                 Value::Array(ref info) => match info.as_slice() {
-                    [_, Value::String(text), Value::Array(user), Value::Array(up), ..] => {
-                        let uid = user.get(0).and_then(|v| v.as_u64()).unwrap_or(0);
+                    [meta, Value::String(text), Value::Array(user), Value::Array(up), rest @ ..] => {
+                        let meta = meta.as_array().map(|m| m.as_slice()).unwrap_or(&[]);
+                        let meta_u64 = |i: usize| meta.get(i).and_then(|v| v.as_u64());
+
+                        let uid = Uid(user.get(0).and_then(|v| v.as_u64()).unwrap_or(0));
                         let uname = user
                             .get(1)
                             .and_then(|v| v.as_str())
@@ -100,8 +146,10 @@ pub mod notification_msg {
                         let card_lv = up.get(0).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
                         let card_name =
                             up.get(1).and_then(|v| v.as_str()).unwrap_or("").to_string();
-                        let up_uid = up.last().and_then(|v| v.as_u64()).unwrap_or(0);
+                        let up_uid = Uid(up.last().and_then(|v| v.as_u64()).unwrap_or(0));
                         let up_name = up.get(2).and_then(|v| v.as_str()).unwrap_or("").to_string();
+                        // info[7]
+                        let guard_level = rest.get(3).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
                         Ok(DanmuMsg {
                             uid,
                             uname,
@@ -109,11 +157,33 @@ pub mod notification_msg {
                             medal_name: card_name,
                             medal_owner_uid: up_uid,
                             medal_owner_name: up_name,
+                            guard_level,
                             text: text.to_string(),
+                            mode: meta_u64(1).unwrap_or(1) as u32,
+                            font_size: meta_u64(2).unwrap_or(25) as u32,
+                            color: meta_u64(3).unwrap_or(0xffffff) as u32,
+                            timestamp: meta_u64(4).unwrap_or(0),
                         })
                     }
                     _ => Err(Error::custom("info format error")),
                 },
+                Value::Object(_) => {
+                    let f = DanmuMsgFields::deserialize(info).map_err(Error::custom)?;
+                    Ok(DanmuMsg {
+                        uid: f.uid,
+                        uname: f.uname,
+                        medal_lv: f.medal_lv,
+                        medal_name: f.medal_name,
+                        medal_owner_uid: f.medal_owner_uid,
+                        medal_owner_name: f.medal_owner_name,
+                        guard_level: f.guard_level,
+                        text: f.text,
+                        mode: f.mode,
+                        font_size: f.font_size,
+                        color: f.color,
+                        timestamp: f.timestamp,
+                    })
+                }
                 _ => Err(Error::custom("info type error")),
             }
         }
@@ -122,7 +192,7 @@ pub mod notification_msg {
     #[derive(Deserialize, Serialize, Default, Debug)]
     pub struct EntryEffect {
         #[serde(default)]
-        pub uid: u64,
+        pub uid: Uid,
         #[serde(default)]
         pub copy_writing: String,
     }
@@ -130,7 +200,7 @@ pub mod notification_msg {
     #[derive(Deserialize, Serialize, Debug)]
     pub struct Interact {
         #[serde(default)]
-        pub uid: u64,
+        pub uid: Uid,
         #[serde(default)]
         pub uname: String,
         #[serde(default)]
@@ -147,7 +217,7 @@ pub mod notification_msg {
 
     #[derive(Deserialize, Serialize, Default, Debug)]
     pub struct Medal {
-        pub anchor_roomid: u32,
+        pub anchor_roomid: RoomId,
         pub guard_level: u32,
         pub medal_level: u32,
         pub medal_name: String,
@@ -157,10 +227,17 @@ pub mod notification_msg {
     pub struct GuardBuy {
         pub gift_id: u32,
         pub gift_name: String,
+        /// 1 总督 2 提督 3 舰长
         pub guard_level: u32,
         pub num: u32,
-        pub uid: u64,
+        /// 单价 金瓜子
+        #[serde(default)]
+        pub price: u32,
+        pub uid: Uid,
         pub username: String,
+        /// 购买时间 秒
+        #[serde(default)]
+        pub start_time: u64,
     }
 
     #[derive(Deserialize, Serialize, Debug)]
@@ -170,9 +247,36 @@ pub mod notification_msg {
         #[serde(rename = "giftName")]
         pub gift_name: String,
         pub total_coin: u32,
+        /// gold 金瓜子 silver 银瓜子
+        #[serde(default)]
+        pub coin_type: String,
         pub num: u32,
-        pub uid: u64,
+        pub uid: Uid,
         pub uname: String,
+        #[serde(default)]
+        pub tid: String,
+        /// 同一次批量连击的 `SEND_GIFT` 与 `COMBO_SEND` 相同
+        #[serde(default)]
+        pub batch_combo_id: String,
+        #[serde(default)]
+        pub combo_send: Option<ComboSend>,
+    }
+
+    impl OneGift {
+        pub fn combo_id(&self) -> &str {
+            self.combo_send
+                .as_ref()
+                .map(|c| c.combo_id.as_str())
+                .unwrap_or("")
+        }
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct ComboSend {
+        #[serde(default)]
+        pub combo_id: String,
+        #[serde(default)]
+        pub combo_num: u32,
     }
 
     #[derive(Deserialize, Serialize, Debug)]
@@ -181,8 +285,12 @@ pub mod notification_msg {
         pub gift_name: String,
         pub total_num: u32,
         pub combo_total_coin: u32,
-        pub uid: u64,
+        pub uid: Uid,
         pub uname: String,
+        #[serde(default)]
+        pub combo_id: String,
+        #[serde(default)]
+        pub batch_combo_id: String,
     }
 }
 #[derive(Debug)]
@@ -190,151 +298,28 @@ pub enum ServerLiveMessage {
     LoginAck,
     Notification(notification_msg::NotificationMsg),
     ServerHeartBeat,
+    /// 不是服务器发送的,由客户端在连接状态变化时插入
+    Connection(ConnectionState),
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    Connecting,
+    Connected { host: String },
+    Disconnected { reason: String },
+    Reconnecting { attempt: u32, delay: Duration },
+    GaveUp,
 }
 
 #[derive(Debug, Clone)]
 pub struct WsLogin {
-    pub room_id: u32,
-    pub uid: u32,
+    pub room_id: RoomId,
+    pub uid: Uid,
     pub key: String,
+    pub protover: u32,
 }
 
 pub enum ClientLiveMessage {
     Login(WsLogin),
     ClientHeartBeat,
 }
-
-impl ClientLiveMessage {
-    pub fn encode(&self) -> Vec<u8> {
-        match self {
-            ClientLiveMessage::Login(WsLogin { room_id, uid, key }) => {
-                let uid = if *uid > 0 { Some(*uid) } else { None };
-                let payload = serde_json::json!({
-                        "uid": uid,
-                        "roomid": *room_id,
-                        "protover": 2,
-                        "platform": "web",
-                        "type": 2,
-                        "key": key})
-                .to_string();
-                let payload_len = payload.len();
-                let package_len = 16 + payload_len;
-
-                let mut package = Vec::<u8>::with_capacity(package_len);
-                package
-                    .write_u32::<NetworkEndian>(package_len as u32)
-                    .unwrap();
-                package.write_u16::<NetworkEndian>(16).unwrap();
-                package.write_u16::<NetworkEndian>(1).unwrap();
-                package.write_u32::<NetworkEndian>(7).unwrap();
-                package.write_u32::<NetworkEndian>(1).unwrap();
-                package.extend_from_slice(payload.as_bytes());
-                package
-            }
-            ClientLiveMessage::ClientHeartBeat => {
-                let payload = b"[object Object]";
-                let payload_len = payload.len();
-                let package_len = 16 + payload_len;
-
-                let mut package = Vec::<u8>::with_capacity(package_len);
-                package.write_u32::<NetworkEndian>(16).unwrap();
-                package.write_u16::<NetworkEndian>(16).unwrap();
-                package.write_u16::<NetworkEndian>(1).unwrap();
-                package.write_u32::<NetworkEndian>(2).unwrap();
-                package.write_u32::<NetworkEndian>(1).unwrap();
-                package.extend_from_slice(payload);
-                package
-            }
-        }
-    }
-}
-
-#[derive(Error, Debug)]
-pub enum MsgDecodeError {
-    #[error("bad header")]
-    BadHeader,
-    #[error("useless msg:type = {0}")]
-    UselessMsg(usize),
-    #[error("inflate error {0}")]
-    InflateError(String),
-    #[error("undefine msg v={pkg_v:?} type={pkg_type:?}")]
-    UndefinedMsg { pkg_v: u16, pkg_type: u32 },
-    #[error("decode body is error {0}")]
-    DecodeBodyError(String),
-}
-
-pub fn decode_from_server(
-    data: Vec<u8>,
-    result_list: &mut LinkedList<ServerLiveMessage>,
-) -> Result<(), MsgDecodeError> {
-    let mut buff_len = data.len();
-    let mut buff = Cursor::new(data);
-    'start: loop {
-        let package_length = buff
-            .read_u32::<NetworkEndian>()
-            .map_err(|_| MsgDecodeError::BadHeader)? as usize;
-        let package_head_length = buff
-            .read_u16::<NetworkEndian>()
-            .map_err(|_| MsgDecodeError::BadHeader)? as usize;
-        let package_version = buff
-            .read_u16::<NetworkEndian>()
-            .map_err(|_| MsgDecodeError::BadHeader)?;
-        let package_type = buff
-            .read_u32::<NetworkEndian>()
-            .map_err(|_| MsgDecodeError::BadHeader)?;
-        let package_other = buff
-            .read_u32::<NetworkEndian>()
-            .map_err(|_| MsgDecodeError::BadHeader)?;
-
-        trace!(
-            "package_version={} package_other={}",
-            package_version,
-            package_other
-        );
-
-        if package_version == 2 {
-            let mut package_body = vec![];
-            let _ = buff.read_to_end(&mut package_body);
-
-            let new_data = inflate::inflate_bytes_zlib(package_body.as_slice())
-                .map_err(|e| MsgDecodeError::InflateError(e))?;
-
-            buff_len = new_data.len();
-            buff = Cursor::new(new_data);
-            // tail call
-            continue 'start;
-        }
-        if package_version > 2 {
-            return Err(MsgDecodeError::UndefinedMsg {
-                pkg_v: package_version,
-                pkg_type: package_type,
-            });
-        }
-
-        let package_body_len = package_length - package_head_length;
-        let mut package_body = vec![0; package_body_len];
-        let _ = buff.read(package_body.as_mut_slice());
-
-        match package_type {
-            3 => result_list.push_back(ServerLiveMessage::ServerHeartBeat),
-            5 => {
-                let notification_msg = serde_json::from_slice(package_body.as_slice())
-                    .map_err(|e| MsgDecodeError::DecodeBodyError(e.to_string()))?;
-                result_list.push_back(ServerLiveMessage::Notification(notification_msg))
-            }
-            8 => result_list.push_back(ServerLiveMessage::LoginAck),
-            _ => {
-                return Err(MsgDecodeError::UndefinedMsg {
-                    pkg_v: package_version,
-                    pkg_type: package_type,
-                });
-            }
-        };
-        if buff.position() < buff_len as u64 {
-            continue 'start;
-        } else {
-            break 'start;
-        }
-    }
-    Ok(())
-}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// 决定断线后是否重连以及等待多久
+pub trait ReconnectPolicy: Send + Sync {
+    /// `attempt` 为连续失败次数,从 1 开始。返回 `None` 表示放弃
+    fn next_delay(&self, attempt: u32) -> Option<Duration>;
+
+    /// 连接持续超过这个时间视为稳定,失败次数清零
+    fn reset_after(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+/// 指数退避,延迟在 `[d * (1 - jitter), d * (1 + jitter)]` 之间随机
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    pub base: Duration,
+    pub max: Duration,
+    pub jitter: f64,
+    /// 为 `None` 时不限次数
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if let Some(max_attempts) = self.max_attempts {
+            if attempt > max_attempts {
+                return None;
+            }
+        }
+        let exp = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base
+            .checked_mul(1 << exp)
+            .unwrap_or(self.max)
+            .min(self.max);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        Some(delay.mul_f64(factor))
+    }
+}
+
+/// 固定间隔,永不放弃
+#[derive(Debug, Clone)]
+pub struct Unlimited {
+    pub delay: Duration,
+}
+
+impl ReconnectPolicy for Unlimited {
+    fn next_delay(&self, _attempt: u32) -> Option<Duration> {
+        Some(self.delay)
+    }
+}
+
+fn default_base_secs() -> u64 {
+    1
+}
+
+fn default_max_secs() -> u64 {
+    300
+}
+
+fn default_jitter() -> f64 {
+    0.3
+}
+
+fn default_delay_secs() -> u64 {
+    10
+}
+
+/// 配置文件中的重连策略
+/// `{"mode": "backoff", "base_secs": 1, "max_secs": 300, "jitter": 0.3, "max_attempts": null}`
+/// 或 `{"mode": "unlimited", "delay_secs": 10}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ReconnectConfig {
+    Backoff {
+        #[serde(default = "default_base_secs")]
+        base_secs: u64,
+        #[serde(default = "default_max_secs")]
+        max_secs: u64,
+        #[serde(default = "default_jitter")]
+        jitter: f64,
+        #[serde(default)]
+        max_attempts: Option<u32>,
+    },
+    Unlimited {
+        #[serde(default = "default_delay_secs")]
+        delay_secs: u64,
+    },
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig::Backoff {
+            base_secs: default_base_secs(),
+            max_secs: default_max_secs(),
+            jitter: default_jitter(),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn build(&self) -> Box<dyn ReconnectPolicy> {
+        match *self {
+            ReconnectConfig::Backoff {
+                base_secs,
+                max_secs,
+                jitter,
+                max_attempts,
+            } => Box::new(ExponentialBackoff {
+                base: Duration::from_secs(base_secs),
+                max: Duration::from_secs(max_secs),
+                jitter,
+                max_attempts,
+            }),
+            ReconnectConfig::Unlimited { delay_secs } => Box::new(Unlimited {
+                delay: Duration::from_secs(delay_secs),
+            }),
+        }
+    }
+}
+
+#[test]
+fn backoff_test() {
+    let policy = ExponentialBackoff {
+        base: Duration::from_secs(1),
+        max: Duration::from_secs(60),
+        jitter: 0.0,
+        max_attempts: Some(10),
+    };
+    assert_eq!(policy.next_delay(1), Some(Duration::from_secs(1)));
+    assert_eq!(policy.next_delay(4), Some(Duration::from_secs(8)));
+    assert_eq!(policy.next_delay(10), Some(Duration::from_secs(60)));
+    assert_eq!(policy.next_delay(11), None);
+
+    let policy = ExponentialBackoff {
+        jitter: 0.5,
+        max_attempts: None,
+        ..policy
+    };
+    for attempt in 1..100 {
+        let delay = policy.next_delay(attempt).unwrap();
+        assert!(delay <= Duration::from_secs(90));
+    }
+
+    let config: ReconnectConfig =
+        serde_json::from_str(r#"{"mode": "unlimited", "delay_secs": 5}"#).unwrap();
+    assert_eq!(
+        config.build().next_delay(1000),
+        Some(Duration::from_secs(5))
+    );
+}
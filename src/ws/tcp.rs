@@ -0,0 +1,74 @@
+use crate::bili_api::LiveHost;
+use anyhow::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const BILI_CHAT_SERVER_TCP: (&str, u16) = ("broadcastlv.chat.bilibili.com", 2243);
+/// 单个包的长度上限,超过时认为数据流已经错位
+const MAX_PACKAGE_LEN: usize = 16 * 1024 * 1024;
+const HEADER_LEN: usize = 16;
+
+/// `host_list` 为空时使用默认地址
+pub fn tcp_hosts(host_list: &[LiveHost]) -> Vec<(String, u16)> {
+    let hosts: Vec<(String, u16)> = host_list
+        .iter()
+        .filter(|h| !h.host.is_empty())
+        .map(|h| {
+            let port = if h.port > 0 { h.port as u16 } else { 2243 };
+            (h.host.clone(), port)
+        })
+        .collect();
+    if hosts.is_empty() {
+        let (host, port) = BILI_CHAT_SERVER_TCP;
+        vec![(host.to_string(), port)]
+    } else {
+        hosts
+    }
+}
+
+/// 按包头中的长度读出一个完整的包(包含包头),连接在包边界关闭时返回 None
+pub async fn read_package<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut len_buf = [0u8; 4];
+    let mut read = 0;
+    while read < len_buf.len() {
+        let n = reader.read(&mut len_buf[read..]).await?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("tcp closed in package header"));
+        }
+        read += n;
+    }
+    let package_len = u32::from_be_bytes(len_buf) as usize;
+    if !(HEADER_LEN..=MAX_PACKAGE_LEN).contains(&package_len) {
+        return Err(anyhow!("bad package length {}", package_len));
+    }
+    let mut package = vec![0u8; package_len];
+    package[..4].copy_from_slice(&len_buf);
+    reader
+        .read_exact(&mut package[4..])
+        .await
+        .map_err(|e| anyhow!("tcp read package {}", e))?;
+    Ok(Some(package))
+}
+
+#[tokio::test]
+async fn read_package_test() {
+    use crate::ws::message::ClientLiveMessage;
+
+    let heartbeat = ClientLiveMessage::ClientHeartBeat.encode();
+    let mut data = heartbeat.clone();
+    data.extend_from_slice(&heartbeat);
+    let mut reader: &[u8] = &data;
+    assert_eq!(
+        read_package(&mut reader).await.unwrap(),
+        Some(heartbeat.clone())
+    );
+    assert_eq!(read_package(&mut reader).await.unwrap(), Some(heartbeat));
+    assert_eq!(read_package(&mut reader).await.unwrap(), None);
+
+    let mut reader: &[u8] = &[0, 0, 0, 4, 0];
+    assert!(read_package(&mut reader).await.is_err());
+    let mut reader: &[u8] = &[0, 0];
+    assert!(read_package(&mut reader).await.is_err());
+}